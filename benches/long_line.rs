@@ -0,0 +1,39 @@
+// Times commenting out a single multi-megabyte line, as in a minified JS file. Only rewriting the
+// line is measured, not reading the input. `body` builds each output line as a String, while
+// `LineStream::write_line` writes the prefix and then the line straight to the writer. There's no
+// stable bench harness, so this is a plain binary:
+//
+// cargo bench --bench long_line
+
+use std::io;
+use std::time::{Duration, Instant};
+use toggle_comment::{body, try_parse_pattern, CommentingMode, LineStream, Options, Stats, EMPTY_STATE};
+
+const ITERATIONS: u32 = 20;
+
+fn report(name: &str, mib: f64, elapsed: Duration) {
+    let per_iteration = elapsed / ITERATIONS;
+    println!("{}: {:.1} MiB in {:?} per iteration ({:.0} MiB/s)", name, mib, per_iteration, mib / per_iteration.as_secs_f64());
+}
+
+fn main() {
+    let line = "var a=function(b){return b+1};".repeat(1 << 18);
+    let pattern = try_parse_pattern("1").unwrap();
+    let options = Options::new("// ", CommentingMode::Comment);
+    let mib = line.len() as f64 / (1 << 20) as f64;
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let output = body(line.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+        assert_eq!(output[0].len(), line.len() + "// ".len());
+    }
+    report("long_line body", mib, start.elapsed());
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let mut stats = Stats::default();
+        LineStream::new(&pattern, &options).write_line(&mut io::sink(), &line, &mut stats).unwrap();
+        assert_eq!(stats.changed, 1);
+    }
+    report("long_line write_line", mib, start.elapsed());
+}
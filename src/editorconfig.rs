@@ -0,0 +1,95 @@
+// Minimal EditorConfig support (https://editorconfig.org), only as much as is needed to read a
+// custom `comment_prefix` property for a file:
+//
+// root = true
+// [*.{c,h}]
+// comment_prefix = "// "
+//
+// Files are searched from the input's directory upwards until one sets `root = true`. Nearer
+// files take precedence over further ones, and later sections over earlier ones.
+
+use std::{fs, path::Path};
+use regex::Regex;
+
+/// Find the `comment_prefix` that applies to `file_path`, if any .editorconfig sets one
+pub fn comment_prefix(file_path: &Path) -> Option<String> {
+    let file_path = file_path.canonicalize().ok()?;
+    for dir in file_path.ancestors().skip(1) {
+        let contents = match fs::read_to_string(dir.join(".editorconfig")) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+        let relative = file_path.strip_prefix(dir).ok()?.to_string_lossy().replace('\\', "/");
+        let (prefix, is_root) = lookup(&contents, &relative);
+        if prefix.is_some() || is_root {
+            return prefix;
+        }
+    }
+    None
+}
+
+/// Look up `comment_prefix` for a path relative to the config, along with whether it's a root config
+fn lookup(contents: &str, relative: &str) -> (Option<String>, bool) {
+    let mut is_root = false;
+    let mut prefix = None;
+    let mut section: Option<bool> = None;  // whether the current section applies, None before any
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(glob) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(glob_matches(glob, relative));
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(idx) => (line[..idx].trim().to_lowercase(), line[idx+1..].trim()),
+            None => continue,
+        };
+        match (section, key.as_str()) {
+            (None, "root") => is_root = value.eq_ignore_ascii_case("true"),
+            (Some(true), "comment_prefix") => prefix = Some(unquote(value).to_string()),
+            _ => {},
+        }
+    }
+    (prefix, is_root)
+}
+
+/// Values are trimmed, so a prefix with a trailing space has to be quoted
+fn unquote(value: &str) -> &str {
+    value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value)
+}
+
+/// Globs without a `/` match the file name in any directory, others match from the config's directory
+fn glob_matches(glob: &str, relative: &str) -> bool {
+    let glob = glob.strip_prefix('/').unwrap_or(glob);
+    let (glob, target) = if glob.contains('/') {
+        (glob, relative)
+    } else {
+        (glob, relative.rsplit('/').next().unwrap_or(relative))
+    };
+    match Regex::new(&format!("^{}$", glob_to_regex(glob))) {
+        Ok(re) => re.is_match(target),
+        Err(_) => false,
+    }
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::new();
+    let mut chars = glob.chars().peekable();
+    let mut braces = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => { chars.next(); re.push_str(".*"); },
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '{' => { braces += 1; re.push_str("(?:"); },
+            '}' if braces > 0 => { braces -= 1; re.push(')'); },
+            ',' if braces > 0 => re.push('|'),
+            '[' => re.push('['),
+            ']' => re.push(']'),
+            '!' if re.ends_with('[') => re.push('^'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re
+}
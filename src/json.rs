@@ -0,0 +1,215 @@
+// Just enough JSON to write and read back the files toggle-comment produces itself, such as the
+// --log undo record. Numbers are kept as f64 as in JavaScript.
+
+use crate::ToggleError;
+
+#[derive(Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+/// Quote and escape a string as a JSON string literal
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn parse(s: &str) -> Result<Json, ToggleError> {
+    let mut parser = Parser { chars: s.chars().collect(), pos: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos != parser.chars.len() {
+        return Err(ToggleError::Parse("trailing characters after JSON value".into()));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ToggleError> {
+        self.skip_whitespace();
+        match self.next() {
+            Some(found) if found == c => Ok(()),
+            _ => Err(ToggleError::Parse("malformed JSON".into())),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, ToggleError> {
+        for expected in word.chars() {
+            if self.next() != Some(expected) {
+                return Err(ToggleError::Parse("malformed JSON".into()));
+            }
+        }
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, ToggleError> {
+        self.skip_whitespace();
+        match self.peek().ok_or(ToggleError::Parse("unexpected end of JSON".into()))? {
+            'n' => self.keyword("null", Json::Null),
+            't' => self.keyword("true", Json::Bool(true)),
+            'f' => self.keyword("false", Json::Bool(false)),
+            '"' => self.string().map(Json::String),
+            '[' => self.array(),
+            '{' => self.object(),
+            _ => self.number(),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, ToggleError> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(c)) {
+            self.pos += 1;
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        text.parse().map(Json::Number).map_err(|_| ToggleError::Parse("malformed JSON number".into()))
+    }
+
+    fn string(&mut self) -> Result<String, ToggleError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.next().ok_or(ToggleError::Parse("unterminated JSON string".into()))? {
+                '"' => return Ok(out),
+                '\\' => match self.next().ok_or(ToggleError::Parse("unterminated JSON string".into()))? {
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => out.push(self.unicode_escape()?),
+                    c => out.push(c),
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, ToggleError> {
+        let digits: String = (0..4).filter_map(|_| self.next()).collect();
+        u32::from_str_radix(&digits, 16).map_err(|_| ToggleError::Parse("malformed JSON unicode escape".into()))
+    }
+
+    fn unicode_escape(&mut self) -> Result<char, ToggleError> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            // A surrogate pair, written as a second \uXXXX escape
+            if self.next() != Some('\\') || self.next() != Some('u') {
+                return Err(ToggleError::Parse("malformed JSON unicode escape".into()));
+            }
+            let low = self.hex4()?;
+            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or(ToggleError::Parse("malformed JSON unicode escape".into()))
+    }
+
+    fn array(&mut self) -> Result<Json, ToggleError> {
+        self.expect('[')?;
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(ToggleError::Parse("malformed JSON array".into())),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, ToggleError> {
+        self.expect('{')?;
+        let mut fields = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(ToggleError::Parse("malformed JSON object".into())),
+            }
+        }
+    }
+}
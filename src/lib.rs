@@ -0,0 +1,2033 @@
+// toggle-comment   Andrew Haigh <hello@nelf.in>    2020 CE
+//
+// Address pattern parsing and line-commenting operations backing the toggle-comment binary.
+
+use regex::{Regex, RegexBuilder};
+use clap::arg_enum;
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use std::{fmt, io};
+use std::io::Write;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::str::Lines;
+
+pub mod editorconfig;
+pub mod json;
+pub mod undo;
+
+// --------------------------------
+// A simplified introduction to vi/ex/ed "address patterns":
+//
+// N                1-indexed line number
+// M,N              a range of lines, 1-indexed inclusive of end
+// M,+N             a range specified by a start and a count
+// ,N               a range from the first line, the same as 1,N
+// +M,-N            a range from the Mth line to the Nth from last, +M,N is the same as M,N
+// -N               the Nth line counting back from the end of input (-1 is the last line)
+// /pattern/        a regular expression
+//
+// As in sed, a range whose end /pattern/ never matches runs to the end of input, whether it
+// starts at a line number or a /pattern/.
+//
+// --balanced O C   extends a single address to the nested O ... C block that follows it
+// --indent-block   extends a single address to the following lines indented deeper than it,
+//                  ignoring comment markers
+// --join N         tests a /pattern/ against every N consecutive lines joined by newlines
+
+#[derive(Clone, Debug)]
+enum AddressComponent {
+    Line(usize),            // N
+    RegexPattern(Regex),    // /pattern/
+    Relative(usize),        // +N
+    FromEnd(usize),         // -N
+    #[allow(dead_code)]
+    Step(usize),            // ~N
+//  Multiple(usize),        // ,~N  (GNU extension)
+}
+
+impl AddressComponent {
+    fn matches(&self, line_number: usize, line: &str) -> bool {
+        match &self {
+            AddressComponent::Line(n) => *n == line_number,
+            AddressComponent::RegexPattern(re) => re.is_match(line),
+            _ => todo!(),
+        }
+    }
+}
+
+// Regex has no notion of equality, so compare patterns by their source text
+impl PartialEq for AddressComponent {
+    fn eq(&self, other: &Self) -> bool {
+        use AddressComponent::*;
+        match (self, other) {
+            (Line(a), Line(b)) | (Relative(a), Relative(b)) | (FromEnd(a), FromEnd(b)) | (Step(a), Step(b)) => a == b,
+            (RegexPattern(a), RegexPattern(b)) => a.as_str() == b.as_str(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+enum Address {
+    ZeroAddress,  // FIXME: treat zero addresses as a range of whole file
+    OneAddress(AddressComponent),
+    AddressRange(AddressComponent, AddressComponent),
+    BalancedBlock(AddressComponent, char, char),  // anchor, open, close
+    IndentBlock(AddressComponent),                 // anchor
+    JoinedRegex(AddressComponent, usize),          // /pattern/, lines per window
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressPattern {
+    pattern: Address,
+    negated: bool,
+}
+
+#[derive(Debug)]
+pub struct MatchState {
+    left_match: Option<usize>,
+    right_match: Option<usize>,
+    depth: usize,  // nesting depth for balanced blocks, anchor indentation for indent blocks
+}
+pub static EMPTY_STATE: MatchState = MatchState { left_match: None, right_match: None, depth: 0 };
+
+impl MatchState {
+    pub fn unchanged(&self) -> Self { MatchState { left_match: self.left_match, right_match: self.right_match, depth: self.depth } }
+    fn match_left(&self, idx: usize) -> Self { MatchState { left_match: Some(idx), right_match: self.right_match, depth: self.depth } }
+    fn match_right(&self, idx: usize) -> Self { MatchState { left_match: self.left_match, right_match: Some(idx), depth: self.depth } }
+    fn update(&mut self, other: MatchState) {
+        self.left_match = other.left_match;
+        self.right_match = other.right_match;
+        self.depth = other.depth;
+    }
+}
+
+fn is_delimiter(line: &str, delimiter: char) -> bool {
+    let mut chars = line.trim().chars();
+    chars.next() == Some(delimiter) && chars.next().is_none()
+}
+
+use {Address::*, AddressComponent::*};
+impl AddressPattern {
+    fn new_zero() -> AddressPattern {
+        AddressPattern { pattern: ZeroAddress, negated: false }
+    }
+
+    fn new_single(addr: AddressComponent) -> AddressPattern {
+        AddressPattern { pattern: OneAddress(addr), negated: false }
+    }
+
+    fn new_range(start: AddressComponent, end: AddressComponent) -> AddressPattern {
+        AddressPattern { pattern: AddressRange(start, end), negated: false }
+    }
+
+    pub fn invert(self) -> AddressPattern {
+        AddressPattern { pattern: self.pattern, negated: !self.negated }
+    }
+
+    pub fn balanced(self, open: char, close: char) -> Result<AddressPattern, ToggleError> {
+        let anchor = match self.pattern {
+            ZeroAddress => Line(1),
+            OneAddress(anchor @ (Line(_) | RegexPattern(_))) => anchor,
+            _ => return Err(ToggleError::Usage("balanced blocks require a single line or /regex/ address".to_string())),
+        };
+        Ok(AddressPattern { pattern: BalancedBlock(anchor, open, close), negated: self.negated })
+    }
+
+    pub fn indent_block(self) -> Result<AddressPattern, ToggleError> {
+        let anchor = match self.pattern {
+            OneAddress(anchor @ (Line(_) | RegexPattern(_))) => anchor,
+            _ => return Err(ToggleError::Usage("indent blocks require a single line or /regex/ address".to_string())),
+        };
+        Ok(AddressPattern { pattern: IndentBlock(anchor), negated: self.negated })
+    }
+
+    pub fn joined(self, window: usize) -> Result<AddressPattern, ToggleError> {
+        let re = match self.pattern {
+            OneAddress(re @ RegexPattern(_)) if window > 0 => re,
+            OneAddress(RegexPattern(_)) => return Err(ToggleError::Usage("--join needs at least one line".to_string())),
+            _ => return Err(ToggleError::Usage("--join requires a single /regex/ address".to_string())),
+        };
+        Ok(AddressPattern { pattern: JoinedRegex(re, window), negated: self.negated })
+    }
+
+    /// Replace -N components with absolute line numbers once the input length is known
+    pub fn resolve_from_end(&mut self, line_count: usize) {
+        let resolve = |c: &mut AddressComponent| {
+            if let FromEnd(n) = *c {
+                *c = Line((line_count + 1).saturating_sub(n));
+            }
+        };
+        match &mut self.pattern {
+            ZeroAddress | JoinedRegex(_, _) => {},
+            OneAddress(addr) | BalancedBlock(addr, _, _) | IndentBlock(addr) => resolve(addr),
+            AddressRange(start, end) => { resolve(start); resolve(end); },
+        }
+    }
+
+    /// Whether every line can be decided without knowing the length of the input
+    pub fn is_forward_only(&self) -> bool {
+        match &self.pattern {
+            ZeroAddress => true,
+            JoinedRegex(_, _) => false,
+            OneAddress(addr) | BalancedBlock(addr, _, _) | IndentBlock(addr) => !matches!(addr, FromEnd(_)),
+            AddressRange(start, end) => !matches!(start, FromEnd(_)) && !matches!(end, FromEnd(_)),
+        }
+    }
+
+    /// Whether this is a numeric range ending before it starts, which selects no lines
+    pub fn is_inverted(&self) -> bool {
+        matches!(&self.pattern, AddressRange(Line(s), Line(e)) if e < s)
+    }
+
+    /// The regex of a plain `/re/` address, whose captures a prefix can refer to as `$1` or `${name}`
+    fn capture_regex(&self) -> Option<&Regex> {
+        match &self.pattern {
+            OneAddress(RegexPattern(re)) if !self.negated => Some(re),
+            _ => None,
+        }
+    }
+
+    fn is_indent_block(&self) -> bool {
+        matches!(&self.pattern, IndentBlock(_))
+    }
+
+    fn is_range(&self) -> bool {
+        matches!(&self.pattern, ZeroAddress | AddressRange(_, _) | BalancedBlock(_, _, _) | IndentBlock(_) | JoinedRegex(_, _))
+    }
+
+    fn matches(&self, line_number: usize, line: &str, state: &MatchState) -> (bool, MatchState) {
+        let (is_match, new_state) = match &self.pattern {
+            Address::ZeroAddress => (true, state.unchanged()),
+            // A lone +N counts from the start, as a leading +N does in a range
+            Address::OneAddress(AddressComponent::Relative(n)) => (line_number == *n, state.unchanged()),
+            Address::OneAddress(AddressComponent::Step(_)) => unreachable!("~N addresses are rejected when parsing"),
+            Address::OneAddress(AddressComponent::FromEnd(_)) => unreachable!("-N addresses are resolved against the input length before matching, see is_forward_only"),
+            Address::OneAddress(addr) => (addr.matches(line_number, line), state.unchanged()),
+            Address::AddressRange(_, _) => self.match_range(line_number, line, state),
+            Address::BalancedBlock(_, _, _) => self.match_balanced(line_number, line, state),
+            Address::IndentBlock(_) => self.match_indented(line_number, line, state),
+            // Line by line, only matches that fit within a single line can be found
+            Address::JoinedRegex(re, _) => (re.matches(line_number, line), state.unchanged()),
+        };
+        if self.negated { (!is_match, new_state) } else { (is_match, new_state) }
+    }
+
+    fn match_range(&self, line_number: usize, line: &str, state: &MatchState) -> (bool, MatchState) {
+        assert!(matches!(&self.pattern, Address::AddressRange { .. }), "Unexpected type");
+        match &self.pattern {
+            AddressRange(Line(s), Line(e)) => {
+                // An end before the start gives an empty range, so nothing matches
+                ((*s..=*e).contains(&line_number), state.unchanged())
+            },
+            AddressRange(Line(s), RegexPattern(e)) => {
+                match state.right_match {
+                    // NOTE: line_number > *s guard captures behaviour with 0,/regex/ addresses
+                    None if e.is_match(line) && line_number > *s => (true, MatchState { left_match: None, right_match: Some(line_number), depth: 0 }),
+                    None if line_number >= *s => (true, state.unchanged()),
+                    _ => (false, state.unchanged()),
+                }
+            },
+            AddressRange(Line(s), Relative(count)) => {
+                ((*s..=s.saturating_add(*count)).contains(&line_number), state.unchanged())
+            },
+            AddressRange(Line(_s), Step(_count)) => unreachable!("~N addresses are rejected when parsing"),
+            AddressRange(RegexPattern(s), Line(e)) => {
+                // A start matching again inside the range doesn't open another block
+                let in_range = state.left_match.is_some() && line_number <= *e;
+                let new_state = if s.is_match(line) && !in_range { state.match_left(line_number) } else { state.unchanged() };
+                (s.is_match(line) || in_range, new_state)
+            },
+            AddressRange(RegexPattern(s), RegexPattern(e)) => {
+                let in_range = state.left_match.is_some() && state.right_match.is_none();
+                if in_range {
+                    // Only the end-regex is checked once a range is open, as in sed
+                    let new_state = if e.is_match(line) { state.match_right(line_number) } else { state.unchanged() };
+                    (true, new_state)
+                } else if s.is_match(line) {
+                    // The end-regex is first checked on the line after the start, so a line
+                    // matching both opens the range without closing it
+                    (true, MatchState { left_match: Some(line_number), right_match: None, depth: 0 })
+                } else {
+                    (false, state.unchanged())
+                }
+            },
+            AddressRange(RegexPattern(s), Relative(count)) => {
+                match state.left_match {
+                    None if s.is_match(line) => (true, MatchState { left_match: Some(line_number), right_match: None, depth: 0 }),
+                    None => (false, state.unchanged()),
+                    Some(last) if line_number > last + count => (false, MatchState { left_match: None, right_match: None, depth: 0 }),  // reset
+                    Some(_) => (true, state.unchanged()),
+                }
+            },
+            AddressRange(RegexPattern(_s), Step(_count)) => unreachable!("~N addresses are rejected when parsing"),
+            _ => unreachable!("Shouldn't have branched into match_range"),
+        }
+    }
+
+    fn match_balanced(&self, line_number: usize, line: &str, state: &MatchState) -> (bool, MatchState) {
+        let (anchor, open, close) = match &self.pattern {
+            BalancedBlock(anchor, open, close) => (anchor, *open, *close),
+            _ => unreachable!("Shouldn't have branched into match_balanced"),
+        };
+        if state.depth > 0 {
+            let depth = if is_delimiter(line, open) {
+                state.depth + 1
+            } else if is_delimiter(line, close) {
+                state.depth - 1
+            } else {
+                state.depth
+            };
+            if depth == 0 {
+                // Block closed, wait for the anchor to match again
+                return (true, MatchState { left_match: None, right_match: Some(line_number), depth: 0 });
+            }
+            return (true, MatchState { depth, ..state.unchanged() });
+        }
+        let anchored = state.left_match.or_else(|| Some(line_number).filter(|&n| anchor.matches(n, line)));
+        match anchored {
+            Some(start) if is_delimiter(line, open) => (true, MatchState { left_match: Some(start), right_match: None, depth: 1 }),
+            Some(start) => (false, state.match_left(start)),
+            None => (false, state.unchanged()),
+        }
+    }
+
+    fn match_indented(&self, line_number: usize, line: &str, state: &MatchState) -> (bool, MatchState) {
+        let anchor = match &self.pattern {
+            IndentBlock(anchor) => anchor,
+            _ => unreachable!("Shouldn't have branched into match_indented"),
+        };
+        let indent = line.len() - line.trim_start().len();
+        // Blank lines don't end a block, they're left alone when commenting anyway
+        if state.left_match.is_some() && (line.trim().is_empty() || indent > state.depth) {
+            return (true, state.unchanged());
+        }
+        if anchor.matches(line_number, line) {
+            (true, MatchState { left_match: Some(line_number), right_match: None, depth: indent })
+        } else {
+            (false, MatchState { left_match: None, right_match: None, depth: 0 })
+        }
+    }
+}
+
+// --------------------------------
+
+/// Errors from parsing patterns, reading input, or combining options
+#[derive(Debug)]
+pub enum ToggleError {
+    Parse(Cow<'static, str>),  // malformed address
+    Io(io::Error),             // input could not be read
+    Regex(regex::Error),       // a regex failed to compile or exceeded the size limit
+    Usage(String),             // invalid option value or combination of options
+}
+
+impl fmt::Display for ToggleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToggleError::Parse(msg) => write!(f, "unable to parse pattern: {}", msg),
+            ToggleError::Io(err) => write!(f, "{}", err),
+            ToggleError::Regex(err) => write!(f, "invalid regex: {}", err),
+            ToggleError::Usage(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ToggleError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ToggleError::Io(err) => Some(err),
+            ToggleError::Regex(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ToggleError {
+    fn from(err: io::Error) -> Self { ToggleError::Io(err) }
+}
+
+impl From<regex::Error> for ToggleError {
+    fn from(err: regex::Error) -> Self { ToggleError::Regex(err) }
+}
+
+/// Compiled program size limit used by `try_parse_pattern`, the same as the regex crate default
+pub const DEFAULT_REGEX_SIZE_LIMIT: usize = 10 * (1 << 20);
+/// Compiled program size limit used by `validate_pattern`
+pub const VALIDATE_REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Parse a `/regex/`, compiled with at most `size_limit` bytes of program, as addresses are
+pub fn try_parse_regex(s: &str, size_limit: usize) -> Result<Regex, ToggleError> {
+    // As in sed, `\/` is a literal slash that doesn't end the regex
+    let x = s.strip_prefix('/').ok_or(ToggleError::Parse("expected a /regex/".into()))?;
+    let x = x.strip_suffix('/')
+        .filter(|inner| (inner.len() - inner.trim_end_matches('\\').len()) % 2 == 0)
+        .ok_or(ToggleError::Parse("unterminated /regex/".into()))?;
+    if x.is_empty() {
+        return Err(ToggleError::Parse("empty /regex/".into()));
+    }
+    let x = x.replace(r"\/", "/");
+    RegexBuilder::new(&x).size_limit(size_limit).build().map_err(ToggleError::Regex)
+}
+
+fn try_parse_component(s: &str, size_limit: usize) -> Result<AddressComponent, ToggleError> {
+    if s.starts_with("/") {
+        return Ok(RegexPattern(try_parse_regex(s, size_limit)?));
+    }
+    if s.starts_with("+") {
+        return Ok(Relative(s.parse().map_err(|_| ToggleError::Parse("unable to parse relative range".into()))?));
+    } else if s.starts_with("~") {
+        return Err(ToggleError::Parse("~N addresses are not supported".into()));
+    } else if let Some(n) = s.strip_prefix("-") {
+        return match n.parse() {
+            Ok(0) | Err(_) => Err(ToggleError::Parse("unable to parse offset from end".into())),
+            Ok(n) => Ok(FromEnd(n)),
+        };
+    } else if let Ok(x) = s.parse() {
+        return Ok(Line(x));
+    }
+    Err(ToggleError::Parse("unable to parse component".into()))
+}
+
+pub fn try_parse_pattern(s: &str) -> Result<AddressPattern, ToggleError> {
+    try_parse_pattern_with_limit(s, DEFAULT_REGEX_SIZE_LIMIT)
+}
+
+pub fn try_parse_pattern_with_limit(s: &str, size_limit: usize) -> Result<AddressPattern, ToggleError> {
+    try_parse_pattern_with(s, size_limit, ',')
+}
+
+/// Split a pattern into its addresses on `separator`, except where it appears inside a `/.../`
+/// regex, so `/a,b/,/c/` is `/a,b/` and `/c/`. A `\/` inside a regex doesn't end it.
+fn split_addresses(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let (mut start, mut in_regex, mut escaped) = (0, false, false);
+    for (idx, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_regex => escaped = true,
+            '/' => in_regex = !in_regex,
+            c if c == separator && !in_regex => {
+                parts.push(&s[start..idx]);
+                start = idx + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Parse a pattern whose addresses are separated by `separator` rather than `,`
+pub fn try_parse_pattern_with(s: &str, size_limit: usize, separator: char) -> Result<AddressPattern, ToggleError> {
+    // Only a single `!` after the last address negates, one inside /.../ belongs to the regex
+    let (s, negated) = match s.strip_suffix('!') {
+        Some(rest) => (rest, true),
+        None => (s, false),
+    };
+    let parts = split_addresses(s, separator);
+    if parts.len() > 2 && parts.iter().any(|part| part.is_empty()) {
+        return Err(ToggleError::Parse(format!("empty address component around {:?}", separator).into()));
+    }
+    if parts.len() > 2 {
+        return Err(ToggleError::Parse(format!("too many addresses around {:?}, expected at most 2", separator).into()));
+    }
+    let pattern = if parts.len() == 1 {
+        if parts[0].is_empty() {
+            Ok(AddressPattern::new_zero())
+        } else {
+            match try_parse_component(parts[0], size_limit)? {
+                Relative(_) => Err(ToggleError::Parse("+N needs an address before it, as in N,+M".into())),
+                addr => Ok(AddressPattern::new_single(addr)),
+            }
+        }
+    } else if parts[0].is_empty() && parts[1].is_empty() {
+        Err(ToggleError::Parse(format!("missing addresses around {:?}", separator).into()))
+    } else {
+        // Empty first address defaults to the first line, ,N is the same as 1,N
+        // A leading +N counts from the start, so +2,-2 trims the first and last lines
+        let left = match parts[0] {
+            "" => Line(1),
+            s => match try_parse_component(s, size_limit)? {
+                Relative(n) => Line(n),
+                left => left,
+            },
+        };
+        let (left, right) = (left, try_parse_component(parts[1], size_limit)?);
+        Ok(AddressPattern::new_range(left, right))
+    };
+    if negated {
+        pattern.map(|p| p.invert())
+    } else {
+        pattern
+    }
+}
+
+/// Check that a pattern is well-formed without keeping the result around. Regexes are compiled
+/// under `VALIDATE_REGEX_SIZE_LIMIT` so that untrusted patterns can't exhaust memory.
+pub fn validate_pattern(s: &str) -> Result<(), ToggleError> {
+    try_parse_pattern_with_limit(s, VALIDATE_REGEX_SIZE_LIMIT).map(|_| ())
+}
+
+/// The start and length of the new-file side of a unified diff hunk header
+fn hunk_range(s: &str) -> Result<(usize, usize), ToggleError> {
+    let hunk = Regex::new(r"^@@ -\d+(?:,\d+)? \+(?P<start>\d+)(?:,(?P<count>\d+))? @@").unwrap();
+    let captures = hunk.captures(s.trim()).ok_or(ToggleError::Parse("unable to parse hunk header".into()))?;
+    let start: usize = captures["start"].parse().map_err(|_| ToggleError::Parse("unable to parse hunk start".into()))?;
+    let count: usize = match captures.name("count") {
+        Some(count) => count.as_str().parse().map_err(|_| ToggleError::Parse("unable to parse hunk length".into()))?,
+        None => 1,
+    };
+    Ok((start, count))
+}
+
+/// Select the new-file side of a unified diff hunk header: `@@ -10,5 +12,6 @@` gives lines 12-17
+pub fn try_parse_hunk(s: &str) -> Result<AddressPattern, ToggleError> {
+    let (start, count) = hunk_range(s)?;
+    if count == 0 {
+        // Nothing was added on the new-file side
+        return Ok(AddressPattern::new_zero().invert());
+    }
+    Ok(AddressPattern::new_range(Line(start), Relative(count - 1)))
+}
+
+pub fn try_parse_delimiter(s: &str) -> Result<char, ToggleError> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(ToggleError::Usage(format!("balanced block delimiters must be a single character, got {:?}", s))),
+    }
+}
+
+arg_enum! {
+    pub enum CommentingMode {
+        Toggle,
+        Comment,
+        Uncomment,
+    }
+}
+
+/// The new-file lines covered by every hunk in a unified diff, such as the output of `git diff -U0`
+pub fn try_parse_diff_lines(diff: &str) -> Result<HashSet<usize>, ToggleError> {
+    let mut lines = HashSet::new();
+    for header in diff.lines().filter(|line| line.starts_with("@@ ")) {
+        let (start, count) = hunk_range(header)?;
+        lines.extend(start..start + count);
+    }
+    Ok(lines)
+}
+
+/// Selects lines by the time they start with, in a chrono `format` such as `%Y-%m-%d`
+#[derive(Debug)]
+pub struct TimeWindow<'a> {
+    pub format: &'a str,
+    pub before: Option<NaiveDateTime>,  // only lines strictly earlier than this
+    pub after: Option<NaiveDateTime>,   // only lines strictly later than this
+}
+
+impl<'a> TimeWindow<'a> {
+    /// Parse the bounds in the same format as the lines
+    pub fn new(format: &'a str, before: Option<&str>, after: Option<&str>) -> Result<Self, ToggleError> {
+        let bound = |s: &str| parse_leading_time(s.trim(), format)
+            .ok_or_else(|| ToggleError::Usage(format!("{:?} doesn't start with a time in the format {:?}", s, format)));
+        Ok(TimeWindow { format, before: before.map(bound).transpose()?, after: after.map(bound).transpose()? })
+    }
+
+    /// Whether the line starts with a time inside the window. Lines without one never are.
+    pub fn matches(&self, line: &str) -> bool {
+        parse_leading_time(line.trim_start(), self.format)
+            .is_some_and(|time| self.before.is_none_or(|before| time < before) && self.after.is_none_or(|after| time > after))
+    }
+}
+
+/// The time at the start of `s`, taking midnight for a format with only a date
+fn parse_leading_time(s: &str, format: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_and_remainder(s, format).map(|(time, _)| time)
+        .or_else(|_| NaiveDate::parse_and_remainder(s, format).map(|(date, _)| date.and_time(NaiveTime::MIN)))
+        .ok()
+}
+
+/// Parse a `START..END` range of byte offsets, END exclusive
+pub fn try_parse_byte_range(s: &str) -> Result<(usize, usize), ToggleError> {
+    let (start, end) = s.split_once("..").ok_or(ToggleError::Parse("expected START..END byte offsets".into()))?;
+    match (start.trim().parse(), end.trim().parse()) {
+        (Ok(start), Ok(end)) if start <= end => Ok((start, end)),
+        _ => Err(ToggleError::Parse("expected START..END byte offsets with START <= END".into())),
+    }
+}
+
+/// The 1-indexed lines whose bytes, newline included, overlap `start..end`. An empty range
+/// selects the line holding `start`. Offsets inside a multi-byte character count for its line.
+pub fn byte_range_lines(contents: &str, start: usize, end: usize) -> HashSet<usize> {
+    let end = end.max(start.saturating_add(1));
+    let mut offset = 0;
+    let mut selected = HashSet::new();
+    for (idx, line) in contents.split_inclusive('\n').enumerate() {
+        let line_end = offset + line.len();
+        if offset < end && start < line_end {
+            selected.insert(idx + 1);
+        }
+        offset = line_end;
+    }
+    selected
+}
+
+/// Parse the `N:` line numbers from `grep -n` style output, where anything after the first : is ignored
+pub fn try_parse_numbered_lines(s: &str) -> Result<HashSet<usize>, ToggleError> {
+    let numbers: Vec<&str> = s.lines().map(|line| line.split(':').next().unwrap_or(line)).collect();
+    try_parse_line_numbers(&numbers.join("\n"))
+}
+
+/// Parse one line number per line, as written by `grep -n ... | cut -d: -f1`, ignoring blank lines
+pub fn try_parse_line_numbers(s: &str) -> Result<HashSet<usize>, ToggleError> {
+    s.lines().map(str::trim).filter(|l| !l.is_empty()).map(|l| match l.parse() {
+        Ok(0) | Err(_) => Err(ToggleError::Parse("line numbers must be positive integers".into())),
+        Ok(n) => Ok(n),
+    }).collect()
+}
+
+/// Parse a `,`-separated list of literal strings, where `\,` is a comma and `\\` a backslash
+pub fn try_parse_any_of(s: &str) -> Result<Vec<String>, ToggleError> {
+    let mut words = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let word = words.last_mut().unwrap();
+        match c {
+            '\\' => word.push(chars.next().ok_or(ToggleError::Parse("trailing backslash in --any-of list".into()))?),
+            ',' => words.push(String::new()),
+            c => word.push(c),
+        }
+    }
+    if words.iter().any(String::is_empty) {
+        return Err(ToggleError::Parse("empty string in --any-of list".into()));
+    }
+    Ok(words)
+}
+
+/// Parse a script of `;`-separated `PATTERN MODE` steps, e.g. `3,7 comment; /TODO/ uncomment`.
+/// As with addresses, a `;` inside a `/.../` regex doesn't end the step.
+pub fn try_parse_script(s: &str) -> Result<Vec<(&str, CommentingMode)>, ToggleError> {
+    let steps = split_addresses(s, ';').into_iter().map(str::trim).filter(|step| !step.is_empty()).map(|step| {
+        let idx = step.rfind(char::is_whitespace).ok_or(ToggleError::Parse("script steps need a pattern and a mode".into()))?;
+        let mode = step[idx..].trim().parse().map_err(|_| ToggleError::Parse("script step mode must be comment, toggle or uncomment".into()))?;
+        Ok((step[..idx].trim(), mode))
+    }).collect::<Result<Vec<_>, ToggleError>>()?;
+    if steps.is_empty() {
+        return Err(ToggleError::Parse("empty script".into()));
+    }
+    Ok(steps)
+}
+
+/// `prefix` followed by `line`, allocated once at its final size
+fn prefixed(prefix: &str, line: &str) -> String {
+    let mut out = String::with_capacity(prefix.len() + line.len());
+    out.push_str(prefix);
+    out.push_str(line);
+    out
+}
+
+/// A line as rewritten by a line operator, borrowing from the input where it can so that adding a
+/// prefix to a long line doesn't copy it. `Display` writes the pieces one after the other.
+#[derive(Debug)]
+pub enum Rewritten<'a> {
+    Prefixed(&'a str, &'a str),  // a prefix, then the line as it was
+    Line(Cow<'a, str>),          // the whole line, borrowed when it's unchanged
+}
+
+impl<'a> Rewritten<'a> {
+    /// Join the pieces into one `String`
+    pub fn into_owned(self) -> String {
+        match self {
+            Rewritten::Prefixed(prefix, line) => prefixed(prefix, line),
+            Rewritten::Line(line) => line.into_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Rewritten<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Rewritten::Prefixed(prefix, line) => {
+                f.write_str(prefix)?;
+                f.write_str(line)
+            },
+            Rewritten::Line(line) => f.write_str(line),
+        }
+    }
+}
+
+impl PartialEq<str> for Rewritten<'_> {
+    fn eq(&self, other: &str) -> bool {
+        match self {
+            Rewritten::Prefixed(prefix, line) => other.len() == prefix.len() + line.len()
+                && other.starts_with(prefix) && other[prefix.len()..] == **line,
+            Rewritten::Line(line) => line == other,
+        }
+    }
+}
+
+fn force_comment_line<'a>(_prefix_pattern: &Regex, prefix: &'a str, line: &'a str) -> Rewritten<'a> {
+    Rewritten::Prefixed(prefix, line)
+}
+
+fn comment_line<'a>(prefix_pattern: &Regex, prefix: &'a str, line: &'a str) -> Rewritten<'a> {
+    if !prefix_pattern.is_match(line) {
+        Rewritten::Prefixed(prefix, line)
+    } else {
+        Rewritten::Line(Cow::Borrowed(line))
+    }
+}
+
+fn toggle_line<'a>(prefix_pattern: &Regex, prefix: &'a str, line: &'a str) -> Rewritten<'a> {
+    if prefix_pattern.is_match(line) {
+        Rewritten::Line(uncommented(prefix_pattern, line))
+    } else {
+        Rewritten::Prefixed(prefix, line)
+    }
+}
+
+fn uncomment_line<'a>(prefix_pattern: &Regex, _prefix: &'a str, line: &'a str) -> Rewritten<'a> {
+    Rewritten::Line(uncommented(prefix_pattern, line))
+}
+
+/// The line with its comment marker removed, borrowed when it had none
+fn uncommented<'a>(prefix_pattern: &Regex, line: &'a str) -> Cow<'a, str> {
+    prefix_pattern.replace(line, "$head$tail")
+}
+
+/// Uncomment by cutting out exactly the bytes of the marker, leaving the rest of the line as it was
+pub fn strip_marker(prefix_pattern: &Regex, line: &str) -> String {
+    let marker = prefix_pattern.captures(line).and_then(|captures| Some(captures.name("head")?.end()..captures.name("tail")?.start()));
+    let mut stripped = line.to_string();
+    if let Some(marker) = marker {
+        stripped.replace_range(marker, "");
+    }
+    stripped
+}
+
+fn comment_lines<S: AsRef<str>>(mode: &CommentingMode, prefix_pattern: &Regex, prefix: &str, lines: &[S], skip_blank: Option<&Regex>) -> Vec<String> {
+    let operator = match mode {
+        CommentingMode::Comment => comment_line,
+        CommentingMode::Toggle => toggle_line,
+        CommentingMode::Uncomment => uncomment_line,
+    };
+
+    lines.iter().map(|line| {
+        let line = line.as_ref();
+        if skip_blank.is_some_and(|blank| blank.is_match(line)) { line.to_string() } else { operator(prefix_pattern, prefix, line).into_owned() }
+    }).collect()
+}
+
+pub fn get_matches<'a>(pattern: &AddressPattern, lines: &[&'a str], initial_state: MatchState) -> Vec<(bool, Vec<&'a str>)> {
+    let (matches, starts) = line_matches(pattern, lines, initial_state);
+    chunk_matches(lines, &matches, &starts)
+}
+
+/// Match each line, also marking the lines where a block opens, i.e. where `left_match` is set to
+/// that line. This keeps touching blocks apart, e.g. two `/s/,/e/` ranges back to back.
+fn line_matches(pattern: &AddressPattern, lines: &[&str], initial_state: MatchState) -> (Vec<bool>, Vec<bool>) {
+    // -N addresses left unresolved by the caller count back from the end of these lines
+    let resolved;
+    let pattern = if pattern.is_forward_only() {
+        pattern
+    } else {
+        let mut copy = pattern.clone();
+        copy.resolve_from_end(lines.len());
+        resolved = copy;
+        &resolved
+    };
+    if let JoinedRegex(RegexPattern(re), window) = &pattern.pattern {
+        let matches = joined_matches(re, lines, *window);
+        return (matches.into_iter().map(|is_match| is_match != pattern.negated).collect(), vec![false; lines.len()]);
+    }
+    lines.iter().enumerate()
+        .scan(initial_state, |state, (idx, &l)| {
+            let (is_match, new_state) = pattern.matches(idx+1, l, state);
+            let opens = new_state.left_match == Some(idx + 1);
+            state.update(new_state);
+            Some((is_match, opens))
+        })
+        .unzip()
+}
+
+/// Find `re` in each window of `window` lines joined by newlines, selecting every line a match covers
+fn joined_matches(re: &Regex, lines: &[&str], window: usize) -> Vec<bool> {
+    let mut matches = vec![false; lines.len()];
+    for start in 0..lines.len() {
+        let joined = lines[start..(start + window).min(lines.len())].join("\n");
+        let line_of = |byte: usize| start + joined.as_bytes()[..byte].iter().filter(|&&b| b == b'\n').count();
+        for m in re.find_iter(&joined) {
+            // A match ending in the newline doesn't reach onto the next line
+            let last = if m.end() > m.start() { m.end() - 1 } else { m.start() };
+            for is_match in &mut matches[line_of(m.start())..=line_of(last)] {
+                *is_match = true;
+            }
+        }
+    }
+    matches
+}
+
+/// Markers for a named region, e.g. `# BEGIN generated` and `# END generated` for `generated`
+pub fn region_markers(name: &str) -> (Regex, Regex) {
+    let marker = |keyword| Regex::new(&format!(r"\b{}\s+{}(?:\s|$)", keyword, regex::escape(name))).unwrap();
+    (marker("BEGIN"), marker("END"))
+}
+
+/// Select the lines strictly between each begin marker and the next end marker. A region that is
+/// never closed selects nothing, and the line number of its begin marker is returned.
+pub fn region_lines(lines: &[&str], begin: &Regex, end: &Regex) -> (Vec<bool>, Option<usize>) {
+    let mut matches = vec![false; lines.len()];
+    let mut open: Option<usize> = None;
+    for (idx, line) in lines.iter().enumerate() {
+        match open {
+            Some(start) if end.is_match(line) => {
+                matches[start + 1..idx].iter_mut().for_each(|m| *m = true);
+                open = None;
+            },
+            None if begin.is_match(line) => open = Some(idx),
+            _ => {},
+        }
+    }
+    (matches, open.map(|idx| idx + 1))
+}
+
+/// Compare lines to the baseline by line number, lines past the end of the baseline count as changed
+fn changed_lines(baseline: &str, lines: &[&str]) -> Vec<bool> {
+    let mut old = baseline.lines();
+    lines.iter().map(|&l| old.next() != Some(l)).collect()
+}
+
+/// Group consecutive lines sharing the same match status, starting a new group at each selected
+/// line marked in `starts`
+fn chunk_matches<'a>(lines: &[&'a str], matches: &[bool], starts: &[bool]) -> Vec<(bool, Vec<&'a str>)> {
+    let mut retval: Vec<(bool, Vec<&str>)> = vec![];
+    for ((&l, &matched), &opens) in lines.iter().zip(matches).zip(starts) {
+        match retval.last_mut() {
+            Some((last, v)) if *last == matched && !(matched && opens) => v.push(l),
+            _ => retval.push((matched, vec![l])),
+        }
+    }
+    retval
+}
+
+/// Deselect lines that don't repeat an earlier line, so only later duplicates remain selected
+fn select_duplicates(lines: &[&str], matches: &mut [bool]) {
+    let mut seen = HashSet::new();
+    for (line, is_match) in lines.iter().zip(matches.iter_mut()) {
+        *is_match &= !seen.insert(*line);
+    }
+}
+
+arg_enum! {
+    /// Built-in recognizers for the structure of a line, mostly for INI-like config files
+    pub enum LineShape {
+        Assignment,
+        Section,
+        Comment,
+        Blank,
+    }
+}
+
+impl LineShape {
+    pub fn matches(&self, line: &str, prefix: &str) -> bool {
+        let trimmed = line.trim();
+        let is_comment = (!prefix.trim().is_empty() && trimmed.starts_with(prefix.trim()))
+            || CANDIDATE_MARKERS.iter().any(|marker| trimmed.starts_with(marker));
+        match self {
+            LineShape::Blank => trimmed.is_empty(),
+            LineShape::Comment => is_comment,
+            LineShape::Section => trimmed.len() > 2 && trimmed.starts_with('[') && trimmed.ends_with(']'),
+            // key = value or key: value
+            LineShape::Assignment => !is_comment && trimmed.find(['=', ':']).is_some_and(|idx| {
+                let key = trimmed[..idx].trim();
+                !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || "_-. ".contains(c))
+            }),
+        }
+    }
+}
+
+arg_enum! {
+    /// Which characters may make up a line's indentation before its comment marker
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum IndentChar {
+        Tab,
+        Space,
+        Any,
+    }
+}
+
+impl IndentChar {
+    /// Whether every leading whitespace character of `line` is the allowed one
+    pub fn allows(self, line: &str) -> bool {
+        let mut indent = line.chars().take_while(|c| c.is_whitespace());
+        match self {
+            IndentChar::Tab => indent.all(|c| c == '\t'),
+            IndentChar::Space => indent.all(|c| c == ' '),
+            IndentChar::Any => true,
+        }
+    }
+}
+
+/// Presets for common comment styles, setting the prefix and for block-c the block delimiters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerStyle {
+    Hash,
+    Slash,
+    Semicolon,
+    Dash,
+    Percent,
+    BlockC,
+}
+
+impl MarkerStyle {
+    pub const NAMES: [&'static str; 6] = ["hash", "slash", "semicolon", "dash", "percent", "block-c"];
+
+    pub fn prefix(self) -> &'static str {
+        match self {
+            MarkerStyle::Hash => "# ",
+            MarkerStyle::Slash => "// ",
+            MarkerStyle::Semicolon => "; ",
+            MarkerStyle::Dash => "-- ",
+            MarkerStyle::Percent => "% ",
+            MarkerStyle::BlockC => " * ",
+        }
+    }
+
+    /// Lines opening and closing a commented block, for styles that have them
+    pub fn delimiters(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            MarkerStyle::BlockC => Some(("/*", " */")),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for MarkerStyle {
+    type Err = ToggleError;
+
+    fn from_str(s: &str) -> Result<MarkerStyle, ToggleError> {
+        match s {
+            "hash" => Ok(MarkerStyle::Hash),
+            "slash" => Ok(MarkerStyle::Slash),
+            "semicolon" => Ok(MarkerStyle::Semicolon),
+            "dash" => Ok(MarkerStyle::Dash),
+            "percent" => Ok(MarkerStyle::Percent),
+            "block-c" => Ok(MarkerStyle::BlockC),
+            _ => Err(ToggleError::Parse("unknown marker style".into())),
+        }
+    }
+}
+
+fn will_comment<S: AsRef<str>>(prefix_pattern: &Regex, blank: &Regex, lines: &[S]) -> bool {
+    // Walk once to determine if all-nonblank lines are commented or not
+    for line in lines.iter() {
+        let line = line.as_ref();
+        if blank.is_match(line) {
+            continue;
+        } else if !prefix_pattern.is_match(line) {
+            // Line does not match comment pattern, so we should comment out the whole block
+            return true;
+        }
+    }
+    false
+}
+
+type LineOperator = for<'a> fn(&Regex, &'a str, &'a str) -> Rewritten<'a>;
+
+// The regex crate's \s is Unicode-aware, (?-u:\s) restricts it to ASCII whitespace
+fn whitespace_class(ascii: bool) -> &'static str {
+    if ascii { r"(?-u:\s)" } else { r"\s" }
+}
+
+/// With `boundary`, a marker has to be followed by whitespace or the end of the line to count as
+/// a comment, so that with `--` as the prefix `--[[ x` and `--- x` are left alone
+fn prefix_regex(prefix: &str, ascii: bool, boundary: bool) -> Regex {
+    let ws = whitespace_class(ascii);
+    let tail = if boundary && !prefix.ends_with(char::is_whitespace) { format!("(?:{}.*?)?", ws) } else { ".*?".to_string() };
+    Regex::new(&format!(r"^(?P<head>{}*){}(?P<tail>{})$", ws, regex::escape(prefix), tail)).unwrap()
+}
+
+fn blank_regex(ascii: bool) -> Regex {
+    Regex::new(&format!(r"^{}*$", whitespace_class(ascii))).unwrap()
+}
+
+fn block_operator<S: AsRef<str>>(mode: &CommentingMode, prefix_pattern: &Regex, blank: &Regex, lines: &[S]) -> LineOperator {
+    match mode {
+        CommentingMode::Comment => comment_line,
+        CommentingMode::Uncomment => uncomment_line,
+        CommentingMode::Toggle if will_comment(prefix_pattern, blank, lines) => force_comment_line,
+        CommentingMode::Toggle => uncomment_line,  // otherwise
+    }
+}
+
+pub fn comment_block<S: AsRef<str>>(mode: &CommentingMode, prefix: &str, lines: &[S]) -> Vec<String> {
+    let (prefix_pattern, blank) = (prefix_regex(prefix, false, false), blank_regex(false));
+    let operator = block_operator(mode, &prefix_pattern, &blank, lines);
+    comment_block_with(operator, &prefix_pattern, prefix, lines, Some(&blank))
+}
+
+/// Toggle a block as a whole: comment everything if any non-blank line is uncommented,
+/// otherwise uncomment everything
+pub fn toggle_block<S: AsRef<str>>(prefix_pattern: &Regex, prefix: &str, lines: &[S]) -> Vec<String> {
+    let blank = blank_regex(false);
+    let operator = block_operator(&CommentingMode::Toggle, prefix_pattern, &blank, lines);
+    comment_block_with(operator, prefix_pattern, prefix, lines, Some(&blank))
+}
+
+fn comment_block_with<S: AsRef<str>>(operator: LineOperator, prefix_pattern: &Regex, prefix: &str, lines: &[S], skip_blank: Option<&Regex>) -> Vec<String> {
+    let mut output = vec![];
+
+    for line in lines.iter() {
+        let line = line.as_ref();
+        if skip_blank.is_some_and(|blank| blank.is_match(line)) {
+            output.push(line.to_string());
+            continue;
+        }
+        output.push(operator(prefix_pattern, prefix, line).into_owned());
+    }
+    output
+}
+
+/// Comment every line of a block and pad to the widest line before a closing marker, e.g.
+/// `# short  #` above `# longest #`. Lines matching `skip_blank` are left as they are.
+fn box_block<S: AsRef<str>>(prefix: &str, lines: &[S], skip_blank: Option<&Regex>) -> Vec<String> {
+    let width = lines.iter().map(|l| l.as_ref().chars().count()).max().unwrap_or(0);
+    let marker = prefix.trim_end();
+    lines.iter().map(|l| {
+        let l = l.as_ref();
+        if skip_blank.is_some_and(|blank| blank.is_match(l)) { l.to_string() } else { format!("{}{:<width$} {}", prefix, l, marker, width = width) }
+    }).collect()
+}
+
+/// Undo `box_block`, giving the content of each line, or None if the block isn't a box: every
+/// non-blank line has to start with the prefix and end with the marker, all at the same width
+fn unbox_block<S: AsRef<str>>(prefix: &str, lines: &[S]) -> Option<Vec<String>> {
+    let marker = prefix.trim_end();
+    let closing = format!(" {}", marker);
+    let framed: Vec<&str> = lines.iter().map(AsRef::as_ref).filter(|l| !l.trim().is_empty()).collect();
+    let width = framed.first()?.chars().count();
+    if marker.is_empty() || framed.iter().any(|l| l.chars().count() != width || l.len() < prefix.len() + closing.len()) {
+        return None;
+    }
+    lines.iter().map(|l| {
+        let l = l.as_ref();
+        if l.trim().is_empty() {
+            return Some(l.to_string());
+        }
+        let content = l.strip_prefix(prefix)?.strip_suffix(closing.as_str())?;
+        Some(content.trim_end().to_string())
+    }).collect()
+}
+
+/// Replace whatever leading whitespace each non-blank line has with exactly `column` spaces
+fn reindent_block(lines: &mut [String], column: usize) {
+    for line in lines.iter_mut().filter(|l| !l.trim().is_empty()) {
+        *line = format!("{}{}", " ".repeat(column), line.trim_start());
+    }
+}
+
+/// Literal lines inserted around each matched block, after the block has been commented
+#[derive(Default)]
+pub struct Surround<'a> {
+    pub before: Option<&'a str>,
+    pub after: Option<&'a str>,
+}
+
+/// How matched lines are rewritten
+pub struct Options<'a> {
+    pub prefix: &'a str,
+    pub mode: CommentingMode,
+    pub surround: Surround<'a>,
+    pub skip_marker: Option<Regex>,  // selected lines matching this are passed through untouched
+    pub requires: Option<Regex>,     // only selected lines matching this once uncommented are changed
+    pub number_comments: bool,       // label each newly commented line with a running count
+    pub single_block: bool,          // treat every selected line as one block when toggling
+    pub comment_blank: bool,         // add the prefix to blank lines rather than leaving them
+    pub boxed: bool,                 // close each line with a marker aligned past the longest line
+    pub force: bool,                 // comment mode adds the prefix even to commented lines
+    pub ascii: bool,                 // only ASCII whitespace counts as indentation or blank
+    pub duplicates: bool,            // only select lines repeating an earlier line
+    pub reindent: Option<usize>,     // indent each changed line by exactly this many spaces
+    pub first_per_block: bool,       // only change the first line of each matched block
+    pub strip_trailing: bool,        // trim trailing whitespace from lines that were changed
+    pub baseline: Option<String>,    // select lines differing from this text instead of using the pattern
+    pub protect: Vec<Regex>,         // lines matching any of these, e.g. doc comments, are never changed
+    pub shape: Option<(LineShape, bool)>,  // only select lines of this shape, or not of it when negated
+    pub retab: Option<Retab>,        // normalize the indentation of lines that were changed
+    pub space_boundary: bool,        // a prefix only counts when followed by whitespace or the line end
+    pub and_regexes: Vec<Regex>,     // selected lines must also match every one of these
+    pub tag_line_number: bool,       // tag commented lines with `[LNN] `, and strip it when uncommenting
+    pub line_numbers: Option<HashSet<usize>>,  // select exactly these 1-indexed lines instead of using the pattern
+    pub block_delimiters: Option<(&'a str, &'a str)>,  // wrap commented blocks in these, and drop them when uncommenting
+    pub rejoin: bool,                // join each run of lines uncommented together back into one line
+    pub indent_char: IndentChar,     // lines indented with anything else are passed through untouched
+    pub occurrence: Option<usize>,   // only change the Nth matched block, 1-indexed
+    pub nth: Option<usize>,          // only change the Nth line of each matched block, 1-indexed
+    pub empty_comment_to_blank: bool,  // uncommenting a comment with nothing after the marker leaves ""
+    pub sort: bool,                  // sort each matched block by content, ignoring comment markers
+    pub region: Option<(Regex, Regex)>,  // select lines between these begin and end markers instead of using the pattern
+    pub stripe: Option<(usize, usize)>,  // select every Nth line starting from an offset instead of using the pattern
+    pub any_of: Vec<String>,         // if not empty, select lines containing any of these instead of using the pattern
+    pub mark_whitespace: bool,       // replace whitespace-only lines with a `(whitespace)` comment
+    pub adopt_prefix: bool,          // use the marker of the first comment in each block, if there is one
+    pub record_disabled: bool,       // trail commented lines with DISABLED_TAG, and only uncomment lines bearing it
+    pub prefix_rules: Vec<(Regex, String)>,  // the prefix of the first rule matching a line, or its content once uncommented
+    pub literal_uncomment: bool,     // uncomment by slicing out the marker rather than rebuilding the line from captures
+    pub escape_existing: bool,       // escape markers in lines being commented, and unescape them when uncommenting
+    pub logical_lines: bool,         // match backslash-continued lines as one, selecting all of them together
+    pub remove_all: bool,            // uncommenting strips every stacked marker, e.g. `# # x` becomes `x`
+    pub pad_to_width: bool,          // pad the markers of newly commented lines so each block's content lines up
+    pub sentinels: bool,             // wrap commented blocks in SENTINEL_START and SENTINEL_END lines, dropping them when uncommenting
+    pub time_window: Option<TimeWindow<'a>>,  // select lines by their leading timestamp instead of using the pattern
+}
+
+impl<'a> Options<'a> {
+    pub fn new(prefix: &'a str, mode: CommentingMode) -> Options<'a> {
+        Options {
+            prefix,
+            mode,
+            surround: Surround::default(),
+            skip_marker: None,
+            requires: None,
+            number_comments: false,
+            single_block: false,
+            comment_blank: false,
+            boxed: false,
+            force: false,
+            ascii: false,
+            duplicates: false,
+            reindent: None,
+            first_per_block: false,
+            strip_trailing: false,
+            baseline: None,
+            protect: vec![],
+            shape: None,
+            retab: None,
+            space_boundary: false,
+            and_regexes: vec![],
+            tag_line_number: false,
+            line_numbers: None,
+            block_delimiters: None,
+            rejoin: false,
+            indent_char: IndentChar::Any,
+            occurrence: None,
+            nth: None,
+            empty_comment_to_blank: false,
+            sort: false,
+            region: None,
+            stripe: None,
+            any_of: vec![],
+            mark_whitespace: false,
+            adopt_prefix: false,
+            record_disabled: false,
+            prefix_rules: vec![],
+            literal_uncomment: false,
+            escape_existing: false,
+            logical_lines: false,
+            remove_all: false,
+            pad_to_width: false,
+            sentinels: false,
+            time_window: None,
+        }
+    }
+
+    /// Whether each line can be rewritten as soon as it is read, i.e. nothing depends on later lines
+    pub fn is_streamable(&self, pattern: &AddressPattern) -> bool {
+        let block_toggle = matches!(self.mode, CommentingMode::Toggle) && (pattern.is_range() || self.single_block);
+        let capture_prefix = self.prefix.contains('$') && pattern.capture_regex().is_some();
+        pattern.is_forward_only()
+            && !block_toggle
+            && !capture_prefix
+            && !self.boxed
+            && !self.duplicates
+            && !self.number_comments
+            && !self.tag_line_number
+            && !self.rejoin
+            && !self.sort
+            && !self.adopt_prefix
+            && !self.record_disabled
+            && self.prefix_rules.is_empty()
+            && !self.literal_uncomment
+            && !self.escape_existing
+            && !self.logical_lines
+            && !self.remove_all
+            && !self.pad_to_width
+            && !self.sentinels
+            && self.time_window.is_none()
+            && self.occurrence.is_none()
+            && self.nth.is_none()
+            && !self.first_per_block
+            && self.baseline.is_none()
+            && self.line_numbers.is_none()
+            && self.region.is_none()
+            && self.stripe.is_none()
+            && self.any_of.is_empty()
+            && self.block_delimiters.is_none()
+            && self.reindent.is_none()
+            && self.surround.before.is_none()
+            && self.surround.after.is_none()
+    }
+
+    /// The prefix for this line from the first rule matching it, falling back to `self.prefix`
+    fn rule_prefix(&self, line: &str) -> &str {
+        let body = line.trim_start();
+        self.prefix_rules.iter()
+            .find(|(re, prefix)| re.is_match(line) || body.strip_prefix(prefix.as_str()).is_some_and(|rest| re.is_match(rest)))
+            .map_or(self.prefix, |(_, prefix)| prefix)
+    }
+
+    /// Whether an addressed line also satisfies the per-line selectors
+    fn passes_filters(&self, line: &str) -> bool {
+        self.shape.as_ref().is_none_or(|(shape, negated)| shape.matches(line, self.prefix) != *negated)
+            && self.and_regexes.iter().all(|re| re.is_match(line))
+            && !(self.record_disabled && matches!(self.mode, CommentingMode::Uncomment) && !is_disabled(line, self.prefix))
+    }
+
+    /// Whether `before` was an empty comment, e.g. `   # ` or a bare `#`, to be replaced by ""
+    fn blanks_empty_comment(&self, prefix_pattern: &Regex, before: &str, after: &str) -> bool {
+        if !self.empty_comment_to_blank || self.is_held(prefix_pattern, before) {
+            return false;
+        }
+        let stripped = before != after && uncommented(prefix_pattern, before) == after;
+        // A marker without its trailing space isn't matched by the prefix, so catch it here
+        let bare_marker = matches!(self.mode, CommentingMode::Uncomment) && !self.prefix.trim().is_empty() && before.trim() == self.prefix.trim();
+        (stripped && after.trim().is_empty()) || bare_marker
+    }
+
+    /// With `mark_whitespace`, the comment replacing a selected line of only spaces or tabs. Empty
+    /// lines are left alone, as is everything when uncommenting.
+    fn whitespace_marker(&self, prefix_pattern: &Regex, line: &str) -> Option<String> {
+        let marked = self.mark_whitespace && !matches!(self.mode, CommentingMode::Uncomment)
+            && !line.is_empty() && line.trim().is_empty() && !self.is_held(prefix_pattern, line);
+        if marked { Some(prefixed(self.prefix, "(whitespace)")) } else { None }
+    }
+
+    /// Whether a selected line should be passed through untouched
+    fn is_held(&self, prefix_pattern: &Regex, line: &str) -> bool {
+        if self.skip_marker.as_ref().is_some_and(|re| re.is_match(line)) || self.protect.iter().any(|re| re.is_match(line)) || !self.indent_char.allows(line) {
+            return true;
+        }
+        match &self.requires {
+            Some(re) => !re.is_match(&uncommented(prefix_pattern, line)),
+            None => false,
+        }
+    }
+}
+
+/// Which way a matched block is changed, for an editor replaying a toggle
+#[derive(Debug, PartialEq)]
+pub struct BlockPlan {
+    pub start: usize,              // 1-indexed first line of the block
+    pub end: usize,                // 1-indexed last line, inclusive
+    pub direction: &'static str,   // comment, uncomment, or mixed when lines are toggled one by one
+}
+
+/// The direction `render` takes for each matched block. Ranges and --global-toggle choose one with
+/// `will_comment`, other patterns toggle line by line.
+pub fn plan_blocks(chunks: &[(bool, Vec<&str>)], pattern: &AddressPattern, options: &Options) -> Vec<BlockPlan> {
+    let prefix_pattern = prefix_regex(options.prefix, options.ascii, options.space_boundary);
+    let blank = blank_regex(options.ascii);
+    let unheld = |chunk: &[&str]| -> Vec<String> {
+        chunk.iter().filter(|l| !options.is_held(&prefix_pattern, l)).map(|l| l.to_string()).collect()
+    };
+    let block_direction = |lines: &[String]| if will_comment(&prefix_pattern, &blank, lines) { "comment" } else { "uncomment" };
+    let line_direction = |lines: &[String]| {
+        let nonblank: Vec<&String> = lines.iter().filter(|l| !blank.is_match(l)).collect();
+        if nonblank.iter().all(|l| prefix_pattern.is_match(l)) {
+            "uncomment"
+        } else if nonblank.iter().any(|l| prefix_pattern.is_match(l)) {
+            "mixed"
+        } else {
+            "comment"
+        }
+    };
+    let selected: Vec<String> = chunks.iter().filter(|(is_match, _)| *is_match).flat_map(|(_, chunk)| unheld(chunk)).collect();
+    let global = block_direction(&selected);
+    let mut plan = vec![];
+    let mut line = 1;
+    for (is_match, chunk) in chunks {
+        if *is_match {
+            let direction = match options.mode {
+                CommentingMode::Comment => "comment",
+                CommentingMode::Uncomment => "uncomment",
+                CommentingMode::Toggle if options.single_block => global,
+                CommentingMode::Toggle if pattern.is_range() => block_direction(&unheld(chunk)),
+                CommentingMode::Toggle => line_direction(&unheld(chunk)),
+            };
+            plan.push(BlockPlan { start: line, end: line + chunk.len() - 1, direction });
+        }
+        line += chunk.len();
+    }
+    plan
+}
+
+/// Format a plan as a JSON array, with the lines each block became
+pub fn plan_to_json(plan: &[BlockPlan], output: &[String]) -> String {
+    let blocks: Vec<String> = plan.iter().map(|block| {
+        let lines: Vec<String> = output[block.start - 1..block.end].iter().map(|l| json::quote(l)).collect();
+        format!(
+            r#"{{"start":{},"end":{},"direction":{},"lines":[{}]}}"#,
+            block.start, block.end, json::quote(block.direction), lines.join(","),
+        )
+    }).collect();
+    format!("[{}]", blocks.join(","))
+}
+
+/// Line counts gathered while processing the input
+#[derive(Debug, Default, PartialEq)]
+pub struct Stats {
+    pub lines: usize,
+    pub matched: usize,
+    pub changed: usize,
+}
+
+/// Markers considered by `detect_prefix`, in order of preference when tied
+const CANDIDATE_MARKERS: &[&str] = &["#", "//", ";", "--", "%"];
+
+/// Guess the comment prefix from the most common marker that starts lines, e.g. `// ` for a
+/// file with mostly `// ` comments. Markers only count when followed by whitespace.
+pub fn detect_prefix(contents: &str) -> Option<String> {
+    let mut counts = vec![0; CANDIDATE_MARKERS.len()];
+    for i in contents.lines().filter_map(line_marker) {
+        counts[i] += 1;
+    }
+    let best = counts.iter().enumerate().filter(|(_, &n)| n > 0).max_by_key(|&(i, &n)| (n, std::cmp::Reverse(i)))?;
+    Some(format!("{} ", CANDIDATE_MARKERS[best.0]))
+}
+
+/// The index in `CANDIDATE_MARKERS` of the marker commenting out this line, if any
+fn line_marker(line: &str) -> Option<usize> {
+    let line = line.trim_start();
+    CANDIDATE_MARKERS.iter().position(|marker| line.strip_prefix(marker).is_some_and(|rest| rest.starts_with(char::is_whitespace)))
+}
+
+/// The first line, 1-indexed, of each matched block that is commented with a marker other than the
+/// prefix's, e.g. a `// ` comment in a block toggled with `# `, along with that marker
+pub fn mixed_markers(chunks: &[(bool, Vec<&str>)], prefix: &str) -> Vec<(usize, &'static str)> {
+    let mut mixed = vec![];
+    let mut line = 1;
+    for (is_match, chunk) in chunks {
+        if *is_match {
+            let foreign = chunk.iter().enumerate()
+                .filter_map(|(idx, l)| line_marker(l).map(|i| (line + idx, CANDIDATE_MARKERS[i])))
+                .find(|(_, marker)| *marker != prefix.trim_end());
+            mixed.extend(foreign);
+        }
+        line += chunk.len();
+    }
+    mixed
+}
+
+/// The prefix of the first comment in a block, e.g. `// ` for a block holding `// x`
+pub fn block_marker(lines: &[&str]) -> Option<String> {
+    lines.iter().find_map(|line| line_marker(line)).map(|i| format!("{} ", CANDIDATE_MARKERS[i]))
+}
+
+/// Parse an `ext=marker` pair such as `rs=//`, giving the extension and the prefix `// `
+pub fn try_parse_prefix_mapping(s: &str) -> Result<(&str, String), ToggleError> {
+    let idx = s.find('=').ok_or(ToggleError::Parse("expected ext=prefix".into()))?;
+    let (ext, marker) = (s[..idx].trim_start_matches('.'), s[idx+1..].trim());
+    if ext.is_empty() || marker.is_empty() {
+        return Err(ToggleError::Parse("expected ext=prefix".into()));
+    }
+    Ok((ext, format!("{} ", marker)))
+}
+
+/// Parse a `/regex/=marker` rule such as `/^SELECT/=--`, giving the regex and the prefix `-- `
+pub fn try_parse_prefix_rule(s: &str) -> Result<(Regex, String), ToggleError> {
+    let (re, marker) = s.strip_prefix('/')
+        .and_then(|rest| rest.rsplit_once("/="))
+        .ok_or(ToggleError::Parse("expected /regex/=prefix".into()))?;
+    let marker = marker.trim();
+    if marker.is_empty() {
+        return Err(ToggleError::Parse("expected /regex/=prefix".into()));
+    }
+    Ok((Regex::new(re)?, format!("{} ", marker)))
+}
+
+/// Interpreters and the line comment marker of their language
+const SHEBANG_MARKERS: &[(&str, &str)] = &[
+    ("python", "#"), ("ruby", "#"), ("perl", "#"), ("sh", "#"), ("bash", "#"), ("zsh", "#"), ("fish", "#"),
+    ("node", "//"), ("deno", "//"), ("lua", "--"), ("runghc", "--"),
+];
+
+/// The comment marker expected from the interpreter in a `#!` line, e.g. `#` for `#!/usr/bin/env python3`
+pub fn shebang_marker(contents: &str) -> Option<&'static str> {
+    let shebang = contents.lines().next()?.strip_prefix("#!")?;
+    let mut words = shebang.split_whitespace();
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = words.find(|w| !w.starts_with('-'))?;
+    }
+    // python3.8 and friends share the marker of python
+    let name = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    SHEBANG_MARKERS.iter().find(|(i, _)| *i == name).map(|(_, marker)| *marker)
+}
+
+const BINARY_SNIFF_LEN: usize = 8192;
+
+/// Guess whether input is binary, like grep and git do, from a NUL byte near the start
+pub fn is_binary(contents: &[u8]) -> bool {
+    contents.iter().take(BINARY_SNIFF_LEN).any(|&b| b == 0)
+}
+
+/// Build a banner-style prefix by repeating the marker, e.g. `#` repeated 3 times gives `### `
+pub fn repeat_marker(prefix: &str, count: usize) -> String {
+    format!("{} ", prefix.trim_end().repeat(count))
+}
+
+/// Normalizing the indentation of changed lines, each with a tab stop width
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retab {
+    Expand(usize),    // leading tabs become spaces
+    Unexpand(usize),  // leading spaces become tabs where they fill a whole tab stop
+}
+
+/// Rewrite the indentation following any comment prefix, e.g. `# \tx` to `#         x` when
+/// expanding to 8 columns. Tab stops are counted from the start of the indentation.
+pub fn retab_line(line: &str, prefix: &str, retab: Retab) -> String {
+    let (head, rest) = match line.strip_prefix(prefix) {
+        Some(rest) => (prefix, rest),
+        None => ("", line),
+    };
+    let body = rest.trim_start_matches([' ', '\t']);
+    let tabstop = match retab { Retab::Expand(n) | Retab::Unexpand(n) => n.max(1) };
+    let width = rest[..rest.len() - body.len()].chars().fold(0, |col, c| if c == '\t' { col + tabstop - col % tabstop } else { col + 1 });
+    let indent = match retab {
+        Retab::Expand(_) => " ".repeat(width),
+        Retab::Unexpand(_) => format!("{}{}", "\t".repeat(width / tabstop), " ".repeat(width % tabstop)),
+    };
+    format!("{}{}{}", head, indent, body)
+}
+
+/// Fill in `$1` or `${name}` in the prefix from the captures of `re` on this line
+fn expand_captures(re: &Regex, prefix: &str, line: &str) -> String {
+    match re.captures(line) {
+        Some(captures) => {
+            let mut expanded = String::new();
+            captures.expand(prefix, &mut expanded);
+            expanded
+        },
+        None => prefix.to_string(),
+    }
+}
+
+/// Tag a newly commented line with where it was, e.g. `x = 1` on line 42 becomes `# [L42] x = 1`
+pub fn tag_line_number(before: &str, after: &str, prefix: &str, line_number: usize) -> String {
+    let split = before.bytes().zip(after.bytes()).take_while(|(a, b)| a == b).count();
+    // The common head may run into the prefix when it starts with indentation, e.g. `\t` and `\t# `
+    let start = (0..=split).rev().find(|&i| after.get(i..).is_some_and(|rest| rest.starts_with(prefix)));
+    match start {
+        Some(i) => format!("{}[L{}] {}", &after[..i + prefix.len()], line_number, &after[i + prefix.len()..]),
+        None => after.to_string(),
+    }
+}
+
+/// Escape a comment marker starting the line with a backslash, e.g. `# x` becomes `\# x` and
+/// `\# x` becomes `\\# x`, so nested comments come back unchanged through `unescape_marker`
+pub fn escape_marker(prefix: &str, line: &str) -> String {
+    let marker = prefix.trim_end();
+    let body = line.trim_start();
+    let indent = &line[..line.len() - body.len()];
+    if !marker.is_empty() && body.trim_start_matches('\\').starts_with(marker) {
+        format!("{}\\{}", indent, body)
+    } else {
+        line.to_string()
+    }
+}
+
+/// Undo `escape_marker`, dropping one backslash from before a comment marker starting the line
+pub fn unescape_marker(prefix: &str, line: &str) -> String {
+    let marker = prefix.trim_end();
+    let body = line.trim_start();
+    let indent = &line[..line.len() - body.len()];
+    match body.strip_prefix('\\') {
+        Some(rest) if !marker.is_empty() && rest.trim_start_matches('\\').starts_with(marker) => format!("{}{}", indent, rest),
+        _ => line.to_string(),
+    }
+}
+
+/// Marks the start of a block commented with `--sentinels`, after the prefix
+pub const SENTINEL_START: &str = "<<< toggle-comment start";
+/// Marks the end of a block commented with `--sentinels`, after the prefix
+pub const SENTINEL_END: &str = ">>> toggle-comment end";
+
+/// Trails a line commented out by `--disable`, so `--enable` restores only those lines
+pub const DISABLED_TAG: &str = "disabled-by-toggle-comment";
+
+/// The trailer for the given prefix, e.g. ` # disabled-by-toggle-comment` for `# `
+fn disabled_trailer(prefix: &str) -> String {
+    format!(" {}{}", prefix, DISABLED_TAG)
+}
+
+/// Whether a line was commented out by `--disable`
+pub fn is_disabled(line: &str, prefix: &str) -> bool {
+    line.trim_end().ends_with(&disabled_trailer(prefix))
+}
+
+/// Remove the `[LNN] ` tag added by `tag_line_number` from the start of an uncommented line
+pub fn strip_line_tag(line: &str) -> String {
+    let body = line.trim_start();
+    let indent = &line[..line.len() - body.len()];
+    let tag = body.strip_prefix("[L")
+        .and_then(|rest| rest.split_once("] "))
+        .filter(|(number, _)| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()));
+    match tag {
+        Some((_, rest)) => format!("{}{}", indent, rest),
+        None => line.to_string(),
+    }
+}
+
+/// Pad the markers of newly commented lines with spaces to the widest among them, so the content
+/// of a block starts in one column when its lines got prefixes of different widths, e.g. `//! ` and `// `
+pub fn pad_markers(before: &[&str], after: &mut [String]) {
+    let marker = |before: &str, after: &str| after.strip_suffix(before).filter(|marker| !marker.is_empty()).map(|marker| marker.chars().count());
+    let width = before.iter().zip(after.iter()).filter_map(|(b, a)| marker(b, a)).max().unwrap_or(0);
+    for (before, after) in before.iter().zip(after.iter_mut()) {
+        if let Some(len) = marker(before, after) {
+            let split = after.len() - before.len();
+            after.insert_str(split, &" ".repeat(width - len));
+        }
+    }
+}
+
+/// Join each run of consecutive flagged lines into its first, separated by single spaces, e.g. a
+/// comment hard-wrapped over several lines. Blank lines end a run, like paragraph breaks.
+pub fn rejoin_lines(lines: Vec<String>, flags: &[bool]) -> Vec<String> {
+    let mut joined: Vec<String> = vec![];
+    let mut joining = false;
+    for (line, &flag) in lines.into_iter().zip(flags) {
+        let blank = line.trim().is_empty();
+        match joined.last_mut() {
+            Some(last) if joining && flag && !blank => {
+                last.push(' ');
+                last.push_str(line.trim());
+            },
+            _ => joined.push(line),
+        }
+        joining = flag && !blank;
+    }
+    joined
+}
+
+/// Sort each run of non-blank lines by what's left once every stacked marker is removed and it is
+/// unindented, so commented and uncommented lines interleave. Blank lines stay where they are and
+/// equal lines keep their order.
+fn sort_by_content(lines: &mut [String], prefix_pattern: &Regex) {
+    let content = |line: &str| {
+        let mut content = line.to_string();
+        while let Cow::Owned(stripped) = uncommented(prefix_pattern, &content) {
+            if stripped == content {
+                break;
+            }
+            content = stripped;
+        }
+        content.trim_start().to_string()
+    };
+    for run in lines.split_mut(|line| line.trim().is_empty()) {
+        run.sort_by_cached_key(|line| content(line));
+    }
+}
+
+/// Collapse each run of blank or whitespace-only lines down to its first line
+pub fn squeeze_blank_runs(lines: Vec<String>) -> Vec<String> {
+    let mut previous_blank = false;
+    lines.into_iter().filter(|line| {
+        let blank = line.trim().is_empty();
+        let keep = !(blank && previous_blank);
+        previous_blank = blank;
+        keep
+    }).collect()
+}
+
+/// Number output lines from 1, right-aligned to the widest number, e.g. ` 9 | x` above `10 | y`
+pub fn add_gutter(lines: Vec<String>) -> Vec<String> {
+    let width = lines.len().to_string().len();
+    lines.into_iter().enumerate()
+        .map(|(idx, line)| format!("{:>width$} | {}", idx + 1, line, width = width))
+        .collect()
+}
+
+/// Shorten a line for display to at most `width` characters, ending with `…` when cut
+pub fn truncate_preview(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        return line.to_string();
+    }
+    let mut shown: String = line.chars().take(width.saturating_sub(1)).collect();
+    shown.push('…');
+    shown
+}
+
+/// Apply `operator` to the lines of `chunk` not held back by `is_held`, leaving the rest in place
+fn comment_unheld<F, H>(chunk: &[&str], is_held: H, operator: F) -> Vec<String>
+    where F: Fn(&[&str]) -> Vec<String>, H: Fn(&str) -> bool
+{
+    let selected: Vec<&str> = chunk.iter().copied().filter(|l| !is_held(l)).collect();
+    if selected.len() == chunk.len() {
+        return operator(chunk);
+    }
+    let mut commented = operator(&selected).into_iter();
+    chunk.iter().map(|l| {
+        if is_held(l) { l.to_string() } else { commented.next().unwrap() }
+    }).collect()
+}
+
+pub fn body(contents: Lines, initial_state: MatchState, pattern: &AddressPattern, options: &Options) -> Vec<String> {
+    body_with_stats(contents, initial_state, pattern, options, &mut Stats::default())
+}
+
+pub fn body_with_stats(contents: Lines, initial_state: MatchState, pattern: &AddressPattern, options: &Options, stats: &mut Stats) -> Vec<String> {
+    // TODO: don't collect all these lines
+    let lines: Vec<&str> = contents.collect();
+    render(select(&lines, initial_state, pattern, options), pattern, options, stats)
+}
+
+/// Split lines into runs of consecutive selected or unselected lines
+pub fn select<'a>(lines: &[&'a str], initial_state: MatchState, pattern: &AddressPattern, options: &Options) -> Vec<(bool, Vec<&'a str>)> {
+    // Only a pattern has blocks that can touch, the other selectors leave runs of lines
+    let mut starts = vec![false; lines.len()];
+    let mut matches = if let Some(baseline) = &options.baseline {
+        changed_lines(baseline, lines)
+    } else if let Some(numbers) = &options.line_numbers {
+        (1..=lines.len()).map(|n| numbers.contains(&n)).collect()
+    } else if let Some((begin, end)) = &options.region {
+        region_lines(lines, begin, end).0
+    } else if let Some((step, offset)) = options.stripe {
+        (1..=lines.len()).map(|n| n >= offset && (n - offset) % step.max(1) == 0).collect()
+    } else if let Some(window) = &options.time_window {
+        // Commented-out lines keep their timestamps, so that they can be toggled back
+        let prefix_pattern = prefix_regex(options.prefix, options.ascii, options.space_boundary);
+        lines.iter().map(|line| window.matches(&uncommented(&prefix_pattern, line))).collect()
+    } else if !options.any_of.is_empty() {
+        lines.iter().map(|line| options.any_of.iter().any(|word| line.contains(word.as_str()))).collect()
+    } else if options.logical_lines {
+        // Match each backslash-continued run as one line, then select all of its physical lines
+        let runs = continued_runs(lines);
+        let joined: Vec<String> = runs.iter().map(|run| lines[run.clone()].iter().map(|l| l.strip_suffix('\\').unwrap_or(l)).collect()).collect();
+        let joined: Vec<&str> = joined.iter().map(String::as_str).collect();
+        let (joined_matches, joined_starts) = line_matches(pattern, &joined, initial_state);
+        starts = runs.iter().zip(joined_starts)
+            .flat_map(|(run, opens)| std::iter::once(opens).chain(std::iter::repeat_n(false, run.len() - 1)))
+            .collect();
+        runs.iter().zip(joined_matches)
+            .flat_map(|(run, is_match)| std::iter::repeat_n(is_match, run.len()))
+            .collect()
+    } else if pattern.is_indent_block() {
+        // Indentation is measured without comment markers, so a commented-out block is found again
+        let prefix_pattern = prefix_regex(options.prefix, options.ascii, options.space_boundary);
+        let uncommented: Vec<String> = lines.iter().map(|line| uncommented(&prefix_pattern, line).into_owned()).collect();
+        let uncommented: Vec<&str> = uncommented.iter().map(String::as_str).collect();
+        let (matches, opens) = line_matches(pattern, &uncommented, initial_state);
+        starts = opens;
+        matches
+    } else {
+        let (matches, opens) = line_matches(pattern, lines, initial_state);
+        starts = opens;
+        matches
+    };
+    for (line, is_match) in lines.iter().zip(matches.iter_mut()) {
+        *is_match &= options.passes_filters(line);
+    }
+    if options.duplicates {
+        select_duplicates(lines, &mut matches);
+    }
+    let mut chunks = chunk_matches(lines, &matches, &starts);
+    if let Some(n) = options.occurrence {
+        for (idx, (is_match, _)) in chunks.iter_mut().filter(|(is_match, _)| *is_match).enumerate() {
+            *is_match = idx + 1 == n;
+        }
+    }
+    if let Some(n) = options.nth {
+        chunks = chunks.into_iter().flat_map(|(is_match, chunk)| nth_of_block(is_match, chunk, n)).collect();
+    }
+    chunks
+}
+
+/// The runs of physical lines making up each logical line, where a trailing `\` continues a line
+fn continued_runs(lines: &[&str]) -> Vec<std::ops::Range<usize>> {
+    let mut runs = vec![];
+    let mut start = 0;
+    for (i, line) in lines.iter().enumerate() {
+        if !line.ends_with('\\') || i + 1 == lines.len() {
+            runs.push(start..i + 1);
+            start = i + 1;
+        }
+    }
+    runs
+}
+
+/// Split a matched block so only its Nth line, counting from 1, stays matched. Shorter blocks are skipped.
+fn nth_of_block(is_match: bool, mut chunk: Vec<&str>, n: usize) -> Vec<(bool, Vec<&str>)> {
+    if !is_match || n == 0 || chunk.len() < n {
+        return vec![(false, chunk)];
+    }
+    let after = chunk.split_off(n);
+    let nth = chunk.split_off(n - 1);
+    vec![(false, chunk), (true, nth), (false, after)].into_iter().filter(|(_, lines)| !lines.is_empty()).collect()
+}
+
+/// Comment the selected chunks from `select` and reassemble the output lines
+pub fn render(chunks: Vec<(bool, Vec<&str>)>, pattern: &AddressPattern, options: &Options, stats: &mut Stats) -> Vec<String> {
+    let (mode, prefix) = (&options.mode, options.prefix);
+    let blank = blank_regex(options.ascii);
+    let skip_blank = if options.comment_blank { None } else { Some(&blank) };
+    let mut retval: Vec<String> = vec![];
+    let mut label = 0;
+    let mut line_number = 0;
+    let prefix_pattern = prefix_regex(prefix, options.ascii, options.space_boundary);
+    let capture_regex = pattern.capture_regex().filter(|_| prefix.contains('$'));
+    let fixed_operator: Option<LineOperator> = if options.force && matches!(mode, CommentingMode::Comment) {
+        Some(force_comment_line)
+    } else if options.single_block {
+        // Decide the toggle direction once, over all selected lines
+        let selected: Vec<&str> = chunks.iter()
+            .filter(|(is_match, _)| *is_match)
+            .flat_map(|(_, chunk)| chunk.iter().copied())
+            .filter(|l| !options.is_held(&prefix_pattern, l))
+            .collect();
+        Some(block_operator(mode, &prefix_pattern, &blank, &selected))
+    } else {
+        None
+    };
+    // Whether the output so far leaves a block delimiter open, scanned up to `scanned` lines
+    let (mut in_block, mut scanned) = (false, 0);
+    let mut skip_close = false;
+    let mut chunks = chunks.into_iter().peekable();
+    while let Some((is_match, chunk)) = chunks.next() {
+        let first_line = line_number + 1;
+        line_number += chunk.len();
+        stats.lines += chunk.len();
+        if is_match {
+            // A block that already holds comments keeps to their marker
+            let adopted = if options.adopt_prefix { block_marker(&chunk) } else { None };
+            let prefix = adopted.as_deref().unwrap_or(prefix);
+            let prefix_pattern = match &adopted {
+                Some(adopted) => prefix_regex(adopted, options.ascii, options.space_boundary),
+                None => prefix_pattern.clone(),
+            };
+            let sentinels = options.sentinels.then(|| (prefixed(prefix, SENTINEL_START), prefixed(prefix, SENTINEL_END)));
+            let block_delimiters = sentinels.as_ref().map(|(open, close)| (open.as_str(), close.as_str())).or(options.block_delimiters);
+            // Delimiter lines belong to the block around them rather than being commented themselves
+            let is_delimiter = |line: &str| block_delimiters.is_some_and(|(open, close)| line.trim() == open.trim() || line.trim() == close.trim());
+            let had_delimiters = chunk.iter().any(|l| is_delimiter(l));
+            if let Some((open, close)) = block_delimiters {
+                for line in &retval[scanned..] {
+                    if line.trim() == open.trim() {
+                        in_block = true;
+                    } else if line.trim() == close.trim() {
+                        in_block = false;
+                    }
+                }
+            }
+            scanned = retval.len();
+            let open_before = in_block;
+            let open_after = chunk.iter().fold(in_block, |open, l| match block_delimiters {
+                Some((start, _)) if l.trim() == start.trim() => true,
+                Some((_, end)) if l.trim() == end.trim() => false,
+                _ => open,
+            });
+            let chunk: Vec<&str> = chunk.into_iter().filter(|l| !is_delimiter(l)).collect();
+            let (chunk, rest) = if options.first_per_block { chunk.split_at(chunk.len().min(1)) } else { (&chunk[..], &[][..]) };
+            let mut commented = comment_unheld(chunk, |l| options.is_held(&prefix_pattern, l), |lines| {
+                // A box is taken apart rather than commented again, and without --box only a range
+                // of several lines is taken for one
+                let unboxed = unbox_block(prefix, lines).filter(|_| options.boxed || (pattern.is_range() && lines.len() > 1));
+                if let Some(contents) = unboxed {
+                    match mode {
+                        CommentingMode::Comment => lines.iter().map(|l| l.to_string()).collect(),
+                        _ => contents,
+                    }
+                } else if options.boxed && !matches!(mode, CommentingMode::Uncomment) {
+                    box_block(prefix, lines, skip_blank)
+                } else if let Some(re) = capture_regex {
+                    // Each line gets its own prefix, filled in from what the address captured on it
+                    lines.iter().map(|&line| {
+                        let prefix = expand_captures(re, prefix, line);
+                        let prefix_pattern = prefix_regex(&prefix, options.ascii, options.space_boundary);
+                        match fixed_operator {
+                            Some(operator) => comment_block_with(operator, &prefix_pattern, &prefix, &[line], skip_blank),
+                            None => comment_lines(mode, &prefix_pattern, &prefix, &[line], skip_blank),
+                        }.remove(0)
+                    }).collect()
+                } else if !options.prefix_rules.is_empty() {
+                    lines.iter().map(|&line| {
+                        let prefix = options.rule_prefix(line);
+                        let prefix_pattern = prefix_regex(prefix, options.ascii, options.space_boundary);
+                        match fixed_operator {
+                            Some(operator) => comment_block_with(operator, &prefix_pattern, prefix, &[line], skip_blank),
+                            None => comment_lines(mode, &prefix_pattern, prefix, &[line], skip_blank),
+                        }.remove(0)
+                    }).collect()
+                } else if let Some(operator) = fixed_operator {
+                    comment_block_with(operator, &prefix_pattern, prefix, lines, skip_blank)
+                } else if pattern.is_range() {
+                    let operator = block_operator(mode, &prefix_pattern, &blank, lines);
+                    comment_block_with(operator, &prefix_pattern, prefix, lines, skip_blank)
+                } else {
+                    comment_lines(mode, &prefix_pattern, prefix, lines, skip_blank)
+                }
+            });
+            if let Some(column) = options.reindent {
+                reindent_block(&mut commented, column);
+            }
+            if options.number_comments {
+                for (before, after) in chunk.iter().zip(commented.iter_mut()) {
+                    if after.strip_suffix(before) == Some(prefix) {
+                        label += 1;
+                        *after = format!("{}{} {}", prefix, label, before);
+                    }
+                }
+            }
+            if options.pad_to_width {
+                pad_markers(chunk, &mut commented);
+            }
+            for (idx, (before, after)) in chunk.iter().zip(commented.iter_mut()).enumerate() {
+                if options.blanks_empty_comment(&prefix_pattern, before, after) {
+                    after.clear();
+                }
+                if let Some(marker) = options.whitespace_marker(&prefix_pattern, before) {
+                    *after = marker;
+                }
+                if before == after {
+                    continue;
+                }
+                if options.literal_uncomment && uncommented(&prefix_pattern, before) == *after {
+                    *after = strip_marker(&prefix_pattern, before);
+                }
+                if options.escape_existing {
+                    if *after == prefixed(prefix, before) {
+                        *after = prefixed(prefix, &escape_marker(prefix, before));
+                    } else if uncommented(&prefix_pattern, before) == *after {
+                        *after = unescape_marker(prefix, after);
+                    }
+                }
+                if options.remove_all && uncommented(&prefix_pattern, before) == *after {
+                    loop {
+                        let next = uncommented(&prefix_pattern, after);
+                        if next == *after {
+                            break;
+                        }
+                        *after = next.into_owned();
+                    }
+                }
+                if options.tag_line_number {
+                    *after = if uncommented(&prefix_pattern, before) == *after {
+                        strip_line_tag(after)
+                    } else {
+                        tag_line_number(before, after, prefix, first_line + idx)
+                    };
+                }
+                if options.record_disabled {
+                    let trailer = disabled_trailer(prefix);
+                    match after.trim_end().strip_suffix(trailer.as_str()) {
+                        Some(restored) => *after = restored.to_string(),
+                        None => after.push_str(&trailer),
+                    }
+                }
+                if let Some(retab) = options.retab {
+                    *after = retab_line(after, prefix, retab);
+                }
+                if options.strip_trailing {
+                    after.truncate(after.trim_end().len());
+                }
+            }
+            stats.matched += chunk.len() + rest.len();
+            stats.changed += chunk.iter().zip(&commented).filter(|(before, after)| *before != after).count();
+            // Keep the delimiters around a block that is still commented, or add them to a newly commented one
+            let unchanged = chunk.iter().zip(&commented).all(|(before, after)| before == after);
+            let commenting = chunk.iter().zip(&commented).any(|(before, after)| before != after && uncommented(&prefix_pattern, before) != *after);
+            let delimiters = block_delimiters.filter(|_| if unchanged { had_delimiters } else { commenting } && !chunk.is_empty());
+            // Lines uncommented inside a block are taken out of it, splitting the block around them
+            let split = block_delimiters.filter(|_| !unchanged && !commenting);
+            if options.rejoin {
+                let uncommented: Vec<bool> = chunk.iter().zip(&commented)
+                    .map(|(before, after)| before != after && uncommented(&prefix_pattern, before) == *after)
+                    .collect();
+                commented = rejoin_lines(commented, &uncommented);
+            }
+            if options.sort {
+                sort_by_content(&mut commented, &prefix_pattern);
+            }
+            if let Some((open, close)) = split.filter(|_| open_before) {
+                if retval.last().is_some_and(|l| l.trim() == open.trim()) {
+                    retval.pop();
+                } else {
+                    retval.push(close.to_string());
+                }
+            }
+            retval.extend(options.surround.before.map(str::to_string));
+            retval.extend(delimiters.map(|(open, _)| open.to_string()));
+            retval.extend(commented);
+            retval.extend(rest.iter().map(|s| s.to_string()));
+            retval.extend(delimiters.map(|(_, close)| close.to_string()));
+            retval.extend(options.surround.after.map(str::to_string));
+            if let Some((open, close)) = split.filter(|_| open_after) {
+                let closes_next = matches!(chunks.peek(), Some((false, next)) if next.first().is_some_and(|l| l.trim() == close.trim()));
+                if closes_next {
+                    skip_close = true;
+                } else {
+                    retval.push(open.to_string());
+                }
+            }
+            if split.is_some() {
+                in_block = open_after && !skip_close;
+                scanned = retval.len();
+            }
+        } else {
+            retval.extend(chunk.iter().skip(usize::from(skip_close)).map(|s| s.to_string()));
+            skip_close = false;
+        }
+    }
+    retval
+}
+
+/// Line-at-a-time counterpart to `body` for inputs that never end, see `Options::is_streamable`
+pub struct LineStream<'a> {
+    pattern: &'a AddressPattern,
+    options: &'a Options<'a>,
+    state: MatchState,
+    line_number: usize,
+    prefix_pattern: Regex,
+    blank: Regex,
+}
+
+impl<'a> LineStream<'a> {
+    pub fn new(pattern: &'a AddressPattern, options: &'a Options<'a>) -> LineStream<'a> {
+        let prefix_pattern = prefix_regex(options.prefix, options.ascii, options.space_boundary);
+        let blank = blank_regex(options.ascii);
+        LineStream { pattern, options, state: EMPTY_STATE.unchanged(), line_number: 0, prefix_pattern, blank }
+    }
+
+    pub fn process(&mut self, line: &str, stats: &mut Stats) -> String {
+        self.rewrite(line, stats).into_owned()
+    }
+
+    /// Write the rewritten line and a newline to `out`. A line that only gains a prefix is written
+    /// as the prefix and then the line, without joining them into a new `String` first.
+    pub fn write_line<W: Write>(&mut self, out: &mut W, line: &str, stats: &mut Stats) -> io::Result<()> {
+        writeln!(out, "{}", self.rewrite(line, stats))
+    }
+
+    fn rewrite<'l>(&mut self, line: &'l str, stats: &mut Stats) -> Rewritten<'l> where 'a: 'l {
+        self.line_number += 1;
+        // As in select, indent blocks are measured without comment markers
+        let (is_match, new_state) = if self.pattern.is_indent_block() {
+            self.pattern.matches(self.line_number, &uncommented(&self.prefix_pattern, line), &self.state)
+        } else {
+            self.pattern.matches(self.line_number, line, &self.state)
+        };
+        self.state.update(new_state);
+        let is_match = is_match && self.options.passes_filters(line);
+        stats.lines += 1;
+        let unchanged = Rewritten::Line(Cow::Borrowed(line));
+        if !is_match {
+            return unchanged;
+        }
+        stats.matched += 1;
+        let options = self.options;
+        if let Some(marker) = options.whitespace_marker(&self.prefix_pattern, line) {
+            stats.changed += 1;
+            return Rewritten::Line(Cow::Owned(marker));
+        }
+        let skip_blank = !options.comment_blank && self.blank.is_match(line);
+        if skip_blank || options.is_held(&self.prefix_pattern, line) {
+            return unchanged;
+        }
+        let operator: LineOperator = match options.mode {
+            CommentingMode::Comment if options.force => force_comment_line,
+            CommentingMode::Comment => comment_line,
+            CommentingMode::Toggle => toggle_line,
+            CommentingMode::Uncomment => uncomment_line,
+        };
+        let mut output = operator(&self.prefix_pattern, options.prefix, line);
+        // Only options that edit the result need it joined into one String
+        if options.empty_comment_to_blank || options.retab.is_some() || options.strip_trailing {
+            let mut joined = output.into_owned();
+            if options.blanks_empty_comment(&self.prefix_pattern, line, &joined) {
+                joined.clear();
+            }
+            if let Some(retab) = options.retab.filter(|_| joined != line) {
+                joined = retab_line(&joined, options.prefix, retab);
+            }
+            if options.strip_trailing && joined != line {
+                joined.truncate(joined.trim_end().len());
+            }
+            output = Rewritten::Line(Cow::Owned(joined));
+        }
+        if output != *line {
+            stats.changed += 1;
+        }
+        output
+    }
+}
+
+/// A minimal change to one line, positions are 0-indexed and count UTF-16 code units as in LSP
+#[derive(Debug, PartialEq)]
+pub struct TextEdit {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+    pub new_text: String,
+}
+
+/// Describe each changed line as the insertion or removal between its common head and tail,
+/// e.g. `x = 1` to `# x = 1` inserts `# ` at character 0
+pub fn line_edits<S: AsRef<str>>(before: &[&str], after: &[S]) -> Vec<TextEdit> {
+    let utf16_len = |s: &str| s.chars().map(char::len_utf16).sum::<usize>();
+    before.iter().zip(after).enumerate().filter_map(|(line, (old, new))| {
+        let new = new.as_ref();
+        if *old == new {
+            return None;
+        }
+        let head: usize = old.chars().zip(new.chars()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+        let (old_rest, new_rest) = (&old[head..], &new[head..]);
+        let tail: usize = old_rest.chars().rev().zip(new_rest.chars().rev()).take_while(|(a, b)| a == b).map(|(a, _)| a.len_utf8()).sum();
+        let start = utf16_len(&old[..head]);
+        let end = start + utf16_len(&old_rest[..old_rest.len() - tail]);
+        Some(TextEdit { line, start, end, new_text: new_rest[..new_rest.len() - tail].to_string() })
+    }).collect()
+}
+
+/// Format edits as a JSON array of LSP `TextEdit`s
+pub fn edits_to_json(edits: &[TextEdit]) -> String {
+    let edits: Vec<String> = edits.iter().map(|e| format!(
+        r#"{{"range":{{"start":{{"line":{0},"character":{1}}},"end":{{"line":{0},"character":{2}}}}},"newText":{3}}}"#,
+        e.line, e.start, e.end, json::quote(&e.new_text),
+    )).collect();
+    format!("[{}]", edits.join(","))
+}
+
+/// The 1-indexed lines, with their width in characters, that changing pushed from within
+/// `max_width` to over it. When lines were added or removed they can't be paired up, so any new
+/// line over `max_width` counts instead.
+pub fn widened_lines<S: AsRef<str>>(before: &[&str], after: &[S], max_width: usize) -> Vec<(usize, usize)> {
+    let width = |line: &str| line.chars().count();
+    let paired = before.len() == after.len();
+    let existing: HashSet<&str> = if paired { HashSet::new() } else { before.iter().copied().collect() };
+    after.iter().map(AsRef::as_ref).enumerate().filter(|&(idx, line)| {
+        let pushed = if paired { width(before[idx]) <= max_width } else { !existing.contains(line) };
+        pushed && width(line) > max_width
+    }).map(|(idx, line)| (idx + 1, width(line))).collect()
+}
+
+/// An `ed` script making the same changes, one `c` command per changed line from the last up, then
+/// `w` and `q`. A line of just `.` would end the input, so it is written as `..` and fixed with `s`.
+pub fn ed_script<S: AsRef<str>>(before: &[&str], after: &[S]) -> String {
+    let mut script = String::new();
+    for (idx, (old, new)) in before.iter().zip(after).enumerate().rev() {
+        let new = new.as_ref();
+        if *old == new {
+            continue;
+        }
+        if new == "." {
+            script.push_str(&format!("{}c\n..\n.\ns/^\\.//\n", idx + 1));
+        } else {
+            script.push_str(&format!("{}c\n{}\n.\n", idx + 1, new));
+        }
+    }
+    script.push_str("w\nq\n");
+    script
+}
+
+#[cfg(test)]
+mod test;
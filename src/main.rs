@@ -24,375 +24,1405 @@
 // }
 
 use std::{fs, io};
-use std::{path::Path, io::Read, ffi::{OsString, OsStr}};
+use std::io::{BufRead, Write};
+use std::{path::{Path, PathBuf}, io::Read, ffi::{OsString, OsStr}};
+use std::collections::HashSet;
 use regex::Regex;
-use clap::{Arg, App, crate_version, arg_enum, value_t};
-use std::str::Lines;
+use encoding_rs::Encoding;
+use clap::{Arg, App, AppSettings, ArgMatches, Shell, SubCommand, crate_version, value_t};
+use toggle_comment::*;
 
-// --------------------------------
-// A simplified introduction to vi/ex/ed "address patterns":
-//
-// N                1-indexed line number
-// M,N              a range of lines, 1-indexed inclusive of end
-// M,+N             a range specified by a start and a count
-// /pattern/        a regular expression
-
-enum AddressComponent {
-    Line(usize),            // N
-    RegexPattern(Regex),    // /pattern/
-    Relative(usize),        // +N
-    Step(usize),            // ~N
-//  Multiple(usize),        // ,~N  (GNU extension)
-}
-
-impl AddressComponent {
-    fn matches(&self, line_number: usize, line: &str) -> bool {
-        match &self {
-            AddressComponent::Line(n) => *n == line_number,
-            AddressComponent::RegexPattern(re) => re.is_match(line),
-            _ => todo!(),
-        }
-    }
-}
-
-enum Address {
-    ZeroAddress,  // FIXME: treat zero addresses as a range of whole file
-    OneAddress(AddressComponent),
-    AddressRange(AddressComponent, AddressComponent),
+fn get_bin_name() -> OsString {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let p = Path::new(OsStr::new(&args[0]));
+    p.file_name().unwrap_or(OsStr::new("<UNSET>")).into()
 }
 
-struct AddressPattern {
-    pattern: Address,
-    negated: bool,
+#[cfg(unix)]
+fn is_stream(file_type: &fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file_type.is_fifo() || file_type.is_char_device()
 }
 
-#[derive(Debug)]
-struct MatchState {
-    left_match: Option<usize>,
-    right_match: Option<usize>,
+#[cfg(not(unix))]
+fn is_stream(_file_type: &fs::FileType) -> bool {
+    false
 }
-static EMPTY_STATE: MatchState = MatchState { left_match: None, right_match: None };
 
-impl MatchState {
-    fn unchanged(&self) -> Self { MatchState { left_match: self.left_match, right_match: self.right_match } }
-    fn match_left(&self, idx: usize) -> Self { MatchState { left_match: Some(idx), right_match: self.right_match } }
-    fn match_right(&self, idx: usize) -> Self { MatchState { left_match: self.left_match, right_match: Some(idx) } }
-    fn update(&mut self, other: MatchState) {
-        self.left_match = other.left_match;
-        self.right_match = other.right_match;
+fn read_input(file_path: &str) -> io::Result<Vec<u8>> {
+    let file_type = fs::metadata(file_path)?.file_type();
+    if file_type.is_file() {
+        fs::read(file_path)
+    } else if is_stream(&file_type) {
+        // Named pipes and devices can't be sized up front, read them like stdin
+        let mut buffer = vec![];
+        fs::File::open(file_path)?.read_to_end(&mut buffer)?;
+        Ok(buffer)
+    } else if file_type.is_dir() {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "is a directory"))
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported file type"))
     }
 }
 
-use {Address::*, AddressComponent::*};
-impl AddressPattern {
-    fn new_zero() -> AddressPattern {
-        AddressPattern { pattern: ZeroAddress, negated: false }
-    }
+fn main() {
+    // Check options, do we have a pattern? A filename? A target state?
+    // Open streams
+    // Guess language if not specified
+    // Match lines and set/toggle comment status
+    let default_mode = match get_bin_name().to_str() {
+        Some("comment") => "comment",
+        Some("uncomment") => "uncomment",
+        _ => "toggle",
+    };
 
-    fn new_single(addr: AddressComponent) -> AddressPattern {
-        AddressPattern { pattern: OneAddress(addr), negated: false }
-    }
+    let mode_help = format!("Commenting behaviour [default: {}]", default_mode);
+    let mut app = build_app(&mode_help, default_mode);
+    let args = app.clone().get_matches();
 
-    fn new_range(start: AddressComponent, end: AddressComponent) -> AddressPattern {
-        AddressPattern { pattern: AddressRange(start, end), negated: false }
+    if let Some(completions) = args.subcommand_matches("completions") {
+        let shell = value_t!(completions, "SHELL", Shell).unwrap_or_else(|e| e.exit());
+        app.gen_completions_to("toggle-comment", shell, &mut io::stdout());
+        return;
     }
+    if let Err(e) = run(&args) {
+        eprintln!("toggle-comment: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
 
-    fn invert(self) -> AddressPattern {
-        AddressPattern { pattern: self.pattern, negated: !self.negated }
+/// Why a run ends unsuccessfully: an error, or --check, --keep-going or --strict-width finding
+/// something to report after doing what they could
+#[derive(Debug)]
+enum Failure {
+    Error(ToggleError),
+    WouldChange(usize),  // --check found this many lines that would be changed
+    FilesFailed(usize),  // --keep-going carried on past this many files that failed
+    TooWide(usize),      // --strict-width found this many lines pushed past --max-width
+}
+
+impl Failure {
+    /// Process exit status, 2 for bad invocations as with clap
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Error(ToggleError::Usage(_)) => 2,
+            _ => 1,
+        }
     }
+}
 
-    fn is_range(&self) -> bool {
-        match &self.pattern {
-            ZeroAddress | AddressRange(_, _) => true,
-            _ => false,
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Failure::Error(err) => write!(f, "{}", err),
+            Failure::WouldChange(1) => write!(f, "1 line would be changed"),
+            Failure::WouldChange(n) => write!(f, "{} lines would be changed", n),
+            Failure::FilesFailed(1) => write!(f, "1 file failed"),
+            Failure::FilesFailed(n) => write!(f, "{} files failed", n),
+            Failure::TooWide(1) => write!(f, "1 line would be over --max-width"),
+            Failure::TooWide(n) => write!(f, "{} lines would be over --max-width", n),
         }
     }
+}
 
-    fn matches(&self, line_number: usize, line: &str, state: &MatchState) -> (bool, MatchState) {
-        let (is_match, new_state) = match &self.pattern {
-            Address::ZeroAddress => (true, state.unchanged()),
-            Address::OneAddress(AddressComponent::Relative(_)) => panic!("invalid usage of +N or ~N as first address"),
-            Address::OneAddress(AddressComponent::Step(_)) => panic!("invalid usage of +N or ~N as first address"),
-            Address::OneAddress(addr) => (addr.matches(line_number, line), state.unchanged()),
-            Address::AddressRange(_, _) => self.match_range(line_number, line, state),
-        };
-        if self.negated { (!is_match, new_state) } else { (is_match, new_state) }
+impl From<ToggleError> for Failure {
+    fn from(err: ToggleError) -> Self { Failure::Error(err) }
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self { Failure::Error(ToggleError::Io(err)) }
+}
+
+fn build_app<'a>(mode_help: &'a str, default_mode: &'a str) -> App<'a, 'a> {
+    App::new("toggle-comment")
+        .version(crate_version!())
+        .about("A utility for setting or toggling the line-comment status of lines in text files")
+        .arg(Arg::with_name("comment_mode")
+            .long("mode")
+            .value_name("comment|toggle|uncomment")
+            .help(mode_help)
+            .default_value(default_mode)
+            .hide_default_value(true)
+            .possible_values(&["comment", "toggle", "uncomment"])
+            .case_insensitive(true)
+            .hide_possible_values(true))
+        .arg(Arg::with_name("force")
+            .long("force")
+            .help("In comment mode, add the prefix even to lines that are already commented"))
+        .arg(Arg::with_name("comment_prefix")
+            .value_name("PREFIX")
+            .short("c")
+            .long("comment-prefix")
+            .takes_value(true)
+            .help("Line comment prefix string, which may use $1 for a group captured by a /regex/ PATTERN [default: comment_prefix from .editorconfig, most common existing comment marker, or \"# \"]"))
+        .arg(Arg::with_name("marker_style")
+            .long("marker-style")
+            .value_name("STYLE")
+            .takes_value(true)
+            .possible_values(&MarkerStyle::NAMES)
+            .conflicts_with("comment_prefix")
+            .help("Use a preset comment style, block-c also wrapping commented blocks in /* and */"))
+        .arg(Arg::with_name("disable")
+            .long("disable")
+            .conflicts_with("enable")
+            .help("Comment out matched lines and trail them with a disabled-by-toggle-comment marker, for --enable to restore"))
+        .arg(Arg::with_name("enable")
+            .long("enable")
+            .help("Uncomment only matched lines trailed by the --disable marker, leaving other comments alone"))
+        .arg(Arg::with_name("remove_one")
+            .long("remove-one")
+            .help("Uncommenting strips a single marker from stacked ones, e.g. # # x becomes # x (the default)"))
+        .arg(Arg::with_name("remove_all")
+            .long("remove-all")
+            .overrides_with("remove_one")
+            .help("Uncommenting strips every stacked marker, e.g. # # x becomes x"))
+        .arg(Arg::with_name("escape_existing")
+            .long("escape-existing")
+            .help("Escape comment markers already in lines being commented as \\#, and restore them when uncommenting"))
+        .arg(Arg::with_name("literal_uncomment")
+            .long("literal-uncomment")
+            .help("Uncomment by removing exactly the matched marker, leaving every other byte of the line untouched"))
+        .arg(Arg::with_name("sentinels")
+            .long("sentinels")
+            .conflicts_with("marker_style")
+            .help("Put \"<<< toggle-comment start\" and \">>> toggle-comment end\" comment lines around commented blocks, and remove them when uncommenting"))
+        .arg(Arg::with_name("adopt_prefix")
+            .long("adopt-prefix")
+            .help("Use the marker of the first comment in each matched block for that block, e.g. // in a block holding // comments"))
+        .arg(Arg::with_name("prefix_map")
+            .long("prefix-map")
+            .value_name("EXT=PREFIX")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Use PREFIX for INPUT files ending in .EXT unless -c is given, e.g. rs=// (may be repeated)"))
+        .arg(Arg::with_name("prefix_rule")
+            .long("rule")
+            .value_name("/REGEX/=PREFIX")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Use PREFIX for lines matching REGEX, the first matching rule winning; others use the usual prefix (may be repeated)"))
+        .arg(Arg::with_name("pad_to_width")
+            .long("pad-to-width")
+            .help("Pad the markers of commented lines so each block's content lines up, e.g. with --rule giving //! and //"))
+        .arg(Arg::with_name("space_boundary")
+            .long("prefix-requires-space-boundary")
+            .help("Only treat lines as commented when the prefix is followed by whitespace, e.g. not --[[ for -c --"))
+        .arg(Arg::with_name("repeat_marker")
+            .long("repeat-marker")
+            .value_name("N")
+            .takes_value(true)
+            .help("Repeat the comment prefix N times for banner-style comments, e.g. \"### \""))
+        .arg(Arg::with_name("balanced")
+            .long("balanced")
+            .value_names(&["OPEN", "CLOSE"])
+            .number_of_values(2)
+            .help("Select the nested OPEN ... CLOSE block following the addressed line"))
+        .arg(Arg::with_name("indent_block")
+            .long("indent-block")
+            .alias("block-by-indent")
+            .conflicts_with("balanced")
+            .help("Select the addressed line and the following lines indented deeper than it, measured without comment markers so a commented-out block is found again"))
+        .arg(Arg::with_name("join")
+            .long("join")
+            .value_name("N")
+            .takes_value(true)
+            .conflicts_with_all(&["balanced", "indent_block"])
+            .help("Match the /regex/ against N lines at a time joined by newlines, selecting the lines a match spans"))
+        .arg(Arg::with_name("prepend")
+            .long("prepend")
+            .value_name("LINE")
+            .takes_value(true)
+            .help("Insert LINE before each matched block"))
+        .arg(Arg::with_name("append")
+            .long("append")
+            .value_name("LINE")
+            .takes_value(true)
+            .help("Insert LINE after each matched block"))
+        .arg(Arg::with_name("max_regex_size")
+            .long("max-regex-size")
+            .value_name("BYTES")
+            .takes_value(true)
+            .help("Upper bound on the compiled size of each address or --requires regex [default: 10485760]"))
+        .arg(Arg::with_name("addr_sep")
+            .long("addr-sep")
+            .value_name("CHAR")
+            .takes_value(true)
+            .help("Separate the addresses in PATTERN with CHAR instead of ','"))
+        .arg(Arg::with_name("ascii")
+            .long("ascii")
+            .help("Only treat ASCII whitespace as indentation or blank"))
+        .arg(Arg::with_name("and_regex")
+            .long("and-regex")
+            .value_name("REGEX")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Only select lines that also match REGEX (may be repeated)"))
+        .arg(Arg::with_name("match_shape")
+            .long("match-shape")
+            .value_name("SHAPE")
+            .takes_value(true)
+            .help("Only select lines shaped like an assignment, section, comment or blank, or not when prefixed with !"))
+        .arg(Arg::with_name("indent_char")
+            .long("indent-char")
+            .value_name("CHAR")
+            .takes_value(true)
+            .possible_values(&["tab", "space", "any"])
+            .case_insensitive(true)
+            .default_value("any")
+            .help("Leave lines alone unless they are indented only with tabs or only with spaces"))
+        .arg(Arg::with_name("comment_dupes")
+            .long("comment-dupes")
+            .help("Only select lines that exactly repeat an earlier line"))
+        .arg(Arg::with_name("occurrence")
+            .long("occurrence")
+            .value_name("N")
+            .takes_value(true)
+            .help("Only change the Nth block of matched lines, counting from 1"))
+        .arg(Arg::with_name("logical_lines")
+            .long("logical-lines")
+            .help("Treat lines continued with a trailing \\ as one line when matching PATTERN, selecting all of them"))
+        .arg(Arg::with_name("nth")
+            .long("nth")
+            .value_name("N")
+            .takes_value(true)
+            .help("Only change the Nth line of each matched block, counting from 1; shorter blocks are left alone"))
+        .arg(Arg::with_name("first_per_block")
+            .long("first-per-block")
+            .help("Only change the first line of each matched block"))
+        .arg(Arg::with_name("reindent")
+            .long("reindent")
+            .value_name("N")
+            .takes_value(true)
+            .help("Uncomment matched lines and indent each by exactly N spaces, replacing the indentation recovered"))
+        .arg(Arg::with_name("strip_trailing")
+            .long("strip-trailing-whitespace")
+            .overrides_with("keep_trailing")
+            .help("Trim trailing whitespace from the lines that are changed"))
+        .arg(Arg::with_name("keep_trailing")
+            .long("keep-trailing-whitespace")
+            .overrides_with("strip_trailing")
+            .help("Leave trailing whitespace on changed lines as it is [default]"))
+        .arg(Arg::with_name("expand_tabs")
+            .long("expand-tabs")
+            .conflicts_with("unexpand_tabs")
+            .help("Convert the indentation of changed lines from tabs to spaces"))
+        .arg(Arg::with_name("unexpand_tabs")
+            .long("unexpand-tabs")
+            .help("Convert the indentation of changed lines from spaces to tabs"))
+        .arg(Arg::with_name("tabstop")
+            .long("tabstop")
+            .value_name("N")
+            .takes_value(true)
+            .help("Columns per tab for --expand-tabs and --unexpand-tabs [default: 8]"))
+        .arg(Arg::with_name("skip_marker")
+            .long("skip-marker")
+            .value_name("REGEX")
+            .takes_value(true)
+            .help("Leave lines matching REGEX unchanged even when selected"))
+        .arg(Arg::with_name("protect_regex")
+            .long("protect-regex")
+            .value_name("REGEX")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Never change lines matching REGEX, e.g. doc comments like \"^\\s*///\" (may be repeated)"))
+        .arg(Arg::with_name("requires")
+            .long("requires")
+            .value_name("/REGEX/")
+            .takes_value(true)
+            .help("Only change selected lines matching REGEX once any existing comment prefix is removed"))
+        .arg(Arg::with_name("inverse_block")
+            .long("inverse-block")
+            .help("Comment everything except the matched lines, as a single block"))
+        .arg(Arg::with_name("global_toggle")
+            .long("global-toggle")
+            .help("Decide whether to comment or uncomment once for all matched lines, instead of for each block"))
+        .arg(Arg::with_name("mark_whitespace")
+            .long("mark-whitespace")
+            .help("Replace matched lines of only spaces or tabs with a \"(whitespace)\" comment, leaving empty lines alone"))
+        .arg(Arg::with_name("comment_blank")
+            .long("comment-blank")
+            .help("Comment blank lines too, instead of leaving them unchanged"))
+        .arg(Arg::with_name("box")
+            .long("box")
+            .help("Comment matched blocks as a box, with a closing marker after the longest line. Toggling or uncommenting a box removes it"))
+        .arg(Arg::with_name("number_comments")
+            .long("number-comments")
+            .help("Label each newly commented line with an incrementing number"))
+        .arg(Arg::with_name("tag_line_number")
+            .long("tag-line-number")
+            .help("Tag commented lines with their line number, e.g. `# [L42] x`, and strip the tag when uncommenting"))
+        .arg(Arg::with_name("empty_comment_to_blank")
+            .long("empty-comment-to-blank")
+            .help("Uncomment empty comments such as `# ` or a bare `#` to truly empty lines, without leftover whitespace"))
+        .arg(Arg::with_name("sort")
+            .long("sort")
+            .help("Sort each matched block by content, ignoring comment markers, e.g. for import blocks"))
+        .arg(Arg::with_name("rejoin")
+            .long("rejoin")
+            .help("Join consecutive lines that were uncommented into one line, e.g. a hard-wrapped comment"))
+        .arg(Arg::with_name("script")
+            .long("script")
+            .value_name("STEPS")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "line_buffered"])
+            .help("Apply ;-separated \"PATTERN MODE\" steps in order, e.g. \"3,7 comment; /TODO/ uncomment\""))
+        .arg(Arg::with_name("hunk")
+            .long("hunk")
+            .value_name("HEADER")
+            .takes_value(true)
+            .help("Select the new-file lines of a unified diff hunk header, e.g. \"@@ -10,5 +12,6 @@\""))
+        .arg(Arg::with_name("baseline")
+            .long("baseline")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("hunk")
+            .help("Select the lines that differ from the same line of FILE, instead of using PATTERN"))
+        .arg(Arg::with_name("lines_from")
+            .long("lines-from")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "line_buffered"])
+            .help("Select the lines numbered in FILE, one per line, instead of using PATTERN. Use - to read them from stdin"))
+        .arg(Arg::with_name("region")
+            .long("region")
+            .value_name("NAME")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from"])
+            .help("Select the lines between \"BEGIN NAME\" and \"END NAME\" marker lines, instead of using PATTERN"))
+        .arg(Arg::with_name("stripe")
+            .long("stripe")
+            .value_name("N")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region"])
+            .help("Select every Nth line, starting from --stripe-offset, instead of using PATTERN"))
+        .arg(Arg::with_name("stripe_offset")
+            .long("stripe-offset")
+            .value_name("M")
+            .takes_value(true)
+            .requires("stripe")
+            .help("The first line selected by --stripe [default: 1]"))
+        .arg(Arg::with_name("any_of")
+            .long("any-of")
+            .value_name("WORDS")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region", "stripe"])
+            .help("Select lines containing any of the ,-separated literal WORDS, instead of using PATTERN. Write \\, for a comma"))
+        .arg(Arg::with_name("byte_range")
+            .long("byte-range")
+            .value_name("START..END")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "line_buffered"])
+            .help("Select the lines overlapping bytes START up to but not including END of the input, instead of using PATTERN. START..START selects the line holding START"))
+        .arg(Arg::with_name("filter_cmd")
+            .long("filter-cmd")
+            .value_name("COMMAND")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "byte_range", "line_buffered"])
+            .help("Select the lines numbered N: in the output of the shell COMMAND run over the input, e.g. 'grep -n TODO', instead of using PATTERN"))
+        .arg(Arg::with_name("git_modified")
+            .long("git-modified")
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "byte_range", "filter_cmd", "line_buffered"])
+            .help("Select the lines of each INPUT changed since the last git commit, staged or not, instead of using PATTERN"))
+        .arg(Arg::with_name("before")
+            .long("before")
+            .value_name("TIME")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "byte_range", "filter_cmd", "git_modified", "line_buffered"])
+            .help("Select lines starting with a time earlier than TIME, in the --time-format, instead of using PATTERN"))
+        .arg(Arg::with_name("after")
+            .long("after")
+            .value_name("TIME")
+            .takes_value(true)
+            .conflicts_with_all(&["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "byte_range", "filter_cmd", "git_modified", "line_buffered"])
+            .help("Select lines starting with a time later than TIME, in the --time-format, instead of using PATTERN. May be combined with --before"))
+        .arg(Arg::with_name("time_format")
+            .long("time-format")
+            .value_name("FORMAT")
+            .takes_value(true)
+            .default_value("%Y-%m-%d")
+            .help("The chrono format of the times --before and --after compare, e.g. %Y-%m-%dT%H:%M:%S"))
+        .arg(Arg::with_name("write_matched")
+            .long("write-matched")
+            .value_name("FILE")
+            .takes_value(true)
+            .help("Also write the original matched lines to FILE, like sed's w command"))
+        .arg(Arg::with_name("context_separator")
+            .long("context-separator")
+            .requires("write_matched")
+            .help("Write a -- line between matched blocks that aren't adjacent in the input, like grep"))
+        .arg(Arg::with_name("suffix_out")
+            .long("suffix-out")
+            .value_name("SUFFIX")
+            .takes_value(true)
+            .help("Write each INPUT's result to INPUT with SUFFIX appended, instead of stdout"))
+        .arg(Arg::with_name("in_place")
+            .short("i")
+            .long("in-place")
+            .conflicts_with_all(&["suffix_out", "check", "lsp_edits", "line_buffered"])
+            .help("Replace each INPUT with its result, instead of writing to stdout"))
+        .arg(Arg::with_name("parallel")
+            .long("parallel")
+            .value_name("N")
+            .takes_value(true)
+            .requires("in_place")
+            .conflicts_with_all(&["write_matched", "check"])
+            .help("With --in-place, edit the INPUT files on N threads"))
+        .arg(Arg::with_name("follow_symlinks")
+            .long("follow-symlinks")
+            .requires("in_place")
+            .overrides_with("no_follow_symlinks")
+            .help("With --in-place, edit the file a symlinked INPUT points to, keeping the link (the default)"))
+        .arg(Arg::with_name("no_follow_symlinks")
+            .long("no-follow-symlinks")
+            .requires("in_place")
+            .overrides_with("follow_symlinks")
+            .help("With --in-place, refuse to edit symlinked INPUTs"))
+        .arg(Arg::with_name("dump_ast")
+            .long("dump-ast")
+            .help("Print the parsed address pattern and exit, for debugging patterns"))
+        .arg(Arg::with_name("line_buffered")
+            .long("line-buffered")
+            .conflicts_with_all(&["write_matched", "warn_mixed", "gutter", "ensure_trailing_newline", "check"])
+            .help("Process stdin a line at a time and flush each line, e.g. when following a log"))
+        .arg(Arg::with_name("squeeze")
+            .long("squeeze")
+            .alias("trim-blank-run")
+            .conflicts_with("line_buffered")
+            .help("Collapse runs of blank lines in the output into a single blank line"))
+        .arg(Arg::with_name("ensure_trailing_newline")
+            .long("ensure-trailing-newline")
+            .help("End the output with exactly one newline, even if the input ended with none or several"))
+        .arg(Arg::with_name("gutter")
+            .long("gutter")
+            .conflicts_with_all(&["in_place", "suffix_out", "check", "lsp_edits", "ed_script", "emit_plan"])
+            .help("Prefix each printed line with its line number, leaving the text that is written or reported alone"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .long("quiet")
+            .help("Don't print warnings or summaries to stderr; errors are still reported"))
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .long("verbose")
+            .help("Print warnings about patterns that can't match"))
+        .arg(Arg::with_name("encoding")
+            .long("encoding")
+            .value_name("NAME")
+            .takes_value(true)
+            .conflicts_with_all(&["line_buffered", "log", "undo"])
+            .help("Read and write input in the encoding NAME, e.g. latin1 or shift_jis [default: utf-8]"))
+        .arg(Arg::with_name("warn_mixed")
+            .long("warn-mixed")
+            .help("Warn about matched blocks holding comments with a marker other than the prefix, which toggling won't recognise"))
+        .arg(Arg::with_name("keep_going")
+            .long("keep-going")
+            .help("Report INPUT files that fail and carry on with the rest, exiting with 1 at the end"))
+        .arg(Arg::with_name("max_width")
+            .long("max-width")
+            .value_name("N")
+            .takes_value(true)
+            .conflicts_with("line_buffered")
+            .help("Warn about lines that changing pushes past N characters wide"))
+        .arg(Arg::with_name("strict_width")
+            .long("strict-width")
+            .requires("max_width")
+            .help("Fail instead of warning when --max-width is exceeded, leaving the input unchanged"))
+        .arg(Arg::with_name("binary")
+            .long("binary")
+            .help("Process INPUT files that look binary instead of skipping them, or refusing a single one"))
+        .arg(Arg::with_name("lsp_edits")
+            .long("lsp-edits")
+            .conflicts_with_all(&["check", "line_buffered", "suffix_out"])
+            .help("Print a JSON array of LSP text edits adding or removing comment markers instead of the output"))
+        .arg(Arg::with_name("ed_script")
+            .long("ed-script")
+            .conflicts_with_all(&["check", "lsp_edits", "emit_plan", "line_buffered", "in_place", "suffix_out"])
+            .help("Print an ed script making the changes instead of the output, e.g. for toggle-comment --ed-script 3 FILE | ed -s FILE"))
+        .arg(Arg::with_name("emit_plan")
+            .long("emit-plan")
+            .conflicts_with_all(&["check", "lsp_edits", "line_buffered", "script", "in_place", "suffix_out"])
+            .help("Print a JSON array of the matched blocks, whether each was commented or uncommented, and its resulting lines"))
+        .arg(Arg::with_name("check")
+            .long("check")
+            .conflicts_with("suffix_out")
+            .help("Write nothing, list the lines that would change and exit with 1 if there are any"))
+        .arg(Arg::with_name("preview_width")
+            .long("preview-width")
+            .value_name("N")
+            .takes_value(true)
+            .requires("check")
+            .help("Cut lines listed by --check to N columns, ending with '…'"))
+        .arg(Arg::with_name("log")
+            .long("log")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with_all(&["sentinels", "prepend", "append", "squeeze", "rejoin"])
+            .help("Record the lines changed in each file written by --in-place or --suffix-out to FILE, for --undo"))
+        .arg(Arg::with_name("undo")
+            .long("undo")
+            .value_name("FILE")
+            .takes_value(true)
+            .conflicts_with("log")
+            .help("Restore the files recorded by --log FILE, if they haven't been edited since"))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .help("Print a key=value summary of line counts to stderr"))
+        .arg(Arg::with_name("PATTERN")
+            .help("ed-like address pattern for selecting lines. Use -- before patterns starting with '-'")
+            .required_unless_one(&["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "byte_range", "filter_cmd", "git_modified", "before", "after", "undo"]))
+        .arg(Arg::with_name("INPUT")
+            .help("Sets the input file(s), each processed separately.")
+            .multiple(true))
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("completions")
+            .setting(AppSettings::Hidden)
+            .about("Print a shell completion script")
+            .arg(Arg::with_name("SHELL")
+                .required(true)
+                .possible_values(&["bash", "zsh", "fish"])))
+}
+
+fn run(args: &ArgMatches) -> Result<(), Failure> {
+    // With an option such as --hunk or --region standing in for the pattern, every positional argument is an input file
+    let (pattern_str, inputs): (&str, Vec<&str>) = if ["hunk", "baseline", "script", "lines_from", "region", "stripe", "any_of", "byte_range", "filter_cmd", "git_modified", "before", "after"].iter().any(|&arg| args.is_present(arg)) {
+        ("", args.values_of("PATTERN").into_iter().flatten().chain(args.values_of("INPUT").into_iter().flatten()).collect())
+    } else {
+        (args.value_of("PATTERN").unwrap_or(""), args.values_of("INPUT").into_iter().flatten().collect())
+    };
+    if let Some(log) = args.value_of("undo") {
+        return Ok(undo(log)?);
     }
+    if args.is_present("dump_ast") {
+        println!("{:#?}", build_pattern(args, pattern_str)?);
+        return Ok(());
+    }
+    let line_numbers = match args.value_of("lines_from") {
+        Some("-") if inputs.is_empty() => return Err(ToggleError::Usage("--lines-from - reads line numbers from stdin, so it needs an INPUT file".to_string()).into()),
+        Some("-") => {
+            let mut numbers = String::new();
+            io::stdin().read_to_string(&mut numbers)?;
+            Some(try_parse_line_numbers(&numbers)?)
+        },
+        Some(path) => Some(try_parse_line_numbers(&fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?)?),
+        None => None,
+    };
+    let check = args.is_present("check");
+    if args.is_present("line_buffered") && !inputs.is_empty() {
+        return Err(ToggleError::Usage("--line-buffered streams stdin, so it can't be given INPUT files".to_string()).into());
+    }
+    if args.is_present("in_place") && inputs.is_empty() {
+        return Err(ToggleError::Usage("--in-place needs INPUT files to edit".to_string()).into());
+    }
+    if args.is_present("log") && !args.is_present("in_place") && !args.is_present("suffix_out") {
+        return Err(ToggleError::Usage("--log records the files written by --in-place or --suffix-out".to_string()).into());
+    }
+    if args.is_present("log") && args.value_of("marker_style") == Some("block-c") {
+        return Err(ToggleError::Usage("--log can't record the lines added by --marker-style block-c".to_string()).into());
+    }
+    let ensure_newline = args.is_present("ensure_trailing_newline");
+    let encoding = input_encoding(args)?;
+    let preview_width = if args.is_present("preview_width") {
+        Some(value_t!(args.value_of("preview_width"), usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    let mut would_change = 0;
+    let mut stats = Stats::default();
+    let mut undo_records = vec![];
+    let mut matched_out = match args.value_of("write_matched") {
+        Some(path) if !check => Some(io::BufWriter::new(fs::File::create(path)?)),
+        _ => None,
+    };
 
-    fn match_range(&self, line_number: usize, line: &str, state: &MatchState) -> (bool, MatchState) {
-        assert!(match &self.pattern { Address::AddressRange { .. } => true, _ => false }, "Unexpected type");
-        match &self.pattern {
-            AddressRange(Line(s), Line(e)) => {
-                ((*s..*e+1).contains(&line_number), state.unchanged())
-            },
-            AddressRange(Line(s), RegexPattern(e)) => {
-                match state.right_match {
-                    // NOTE: line_number > *s guard captures behaviour with 0,/regex/ addresses
-                    None if e.is_match(line) && line_number > *s => (true, MatchState { left_match: None, right_match: Some(line_number) }),
-                    None if line_number >= *s => (true, state.unchanged()),
-                    _ => (false, state.unchanged()),
+    if args.is_present("line_buffered") {
+        stream(args, pattern_str, &mut stats)?;
+    } else if inputs.is_empty() {
+        let mut buffer = vec![];
+        io::stdin().read_to_end(&mut buffer)?;
+        let buffer = decode(buffer, encoding)?;
+        let (output, plan) = process(args, pattern_str, None, &buffer, line_numbers.as_ref(), &mut stats, matched_out.as_mut())?;
+        if check {
+            would_change += report_changes("<stdin>", &buffer, &output, preview_width);
+        } else if args.is_present("lsp_edits") {
+            print_edits(&buffer, &output)?;
+        } else if args.is_present("ed_script") {
+            print_ed_script(&buffer, &output)?;
+        } else if args.is_present("emit_plan") {
+            print_plan(&buffer, &output, &plan)?;
+        } else {
+            let output = if args.is_present("gutter") { add_gutter(output) } else { output };
+            write_encoded(&mut io::stdout().lock(), &output, &buffer, ensure_newline, encoding)?;
+        }
+    }
+    let mut failed = 0;
+    // With --parallel every file is edited in place here, leaving none for the loop below
+    let inputs = match args.value_of("parallel") {
+        Some(_) => {
+            let threads = value_t!(args.value_of("parallel"), usize).unwrap_or_else(|e| e.exit());
+            if threads == 0 {
+                return Err(ToggleError::Usage("--parallel needs at least 1 thread".to_string()).into());
+            }
+            match edit_in_parallel(args, pattern_str, &inputs, line_numbers.as_ref(), encoding, threads, &mut stats, &mut undo_records) {
+                Ok(failures) => failed += failures,
+                Err(e) => {
+                    write_log(args, &undo_records)?;
+                    return Err(e);
+                },
+            }
+            vec![]
+        },
+        None => inputs,
+    };
+    let bulk = inputs.len() > 1;
+    for file_path in inputs {
+        // With --keep-going a file that fails is reported and the rest are still processed
+        let result = (|| -> Result<(), Failure> {
+            let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", file_path, e));
+            let in_place = if args.is_present("in_place") {
+                Some(in_place_target(args, file_path).map_err(with_path)?)
+            } else {
+                None
+            };
+            let contents = read_input(file_path).map_err(with_path)?;
+            if skip_binary(args, file_path, &contents, bulk)? {
+                return Ok(());
+            }
+            let contents = decode(contents, encoding).map_err(with_path)?;
+            let (output, plan) = process(args, pattern_str, Some(file_path), &contents, line_numbers.as_ref(), &mut stats, matched_out.as_mut())?;
+            if check {
+                would_change += report_changes(file_path, &contents, &output, preview_width);
+            } else if args.is_present("lsp_edits") {
+                print_edits(&contents, &output)?;
+            } else if args.is_present("ed_script") {
+                print_ed_script(&contents, &output)?;
+            } else if args.is_present("emit_plan") {
+                print_plan(&contents, &output, &plan)?;
+            } else if let Some(suffix) = args.value_of("suffix_out") {
+                let out_path = format!("{}{}", file_path, suffix);
+                let mut written = vec![];
+                write_output(&mut written, &output, &contents, ensure_newline)?;
+                let written = String::from_utf8(written).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if args.is_present("log") {
+                    undo_records.push(undo::UndoRecord::new(&out_path, &contents, &written)?);
                 }
+                fs::write(&out_path, encode(written.into_bytes(), encoding)?)?;
+            } else if let Some(target) = in_place {
+                undo_records.extend(replace_input(args, file_path, &target, &contents, &output, encoding)?);
+            } else {
+                let output = if args.is_present("gutter") { add_gutter(output) } else { output };
+                write_encoded(&mut io::stdout().lock(), &output, &contents, ensure_newline, encoding)?;
+            }
+            Ok(())
+        })();
+        match result {
+            Err(e) if args.is_present("keep_going") => {
+                eprintln!("toggle-comment: {}", e);
+                failed += 1;
             },
-            AddressRange(Line(s), Relative(count)) => {
-                ((*s..*s+*count+1).contains(&line_number), state.unchanged())
-            },
-            AddressRange(Line(s), Step(count)) => todo!(),
-            AddressRange(RegexPattern(s), Line(e)) => {
-                let new_state = if s.is_match(line) { state.match_left(line_number) } else { state.unchanged() };
-                (s.is_match(line) || state.left_match.map_or(false, |_last| line_number <= *e), new_state)
-            },
-            AddressRange(RegexPattern(s), RegexPattern(e)) => {
-                let new_state = if e.is_match(line) { state.match_right(line_number) } else { state.unchanged() };
-                // Reset end-regex match state when start-regex matches
-                let new_state = if s.is_match(line) { MatchState { left_match: Some(line_number), right_match: None } } else { new_state.unchanged() };
-                (s.is_match(line) || (state.left_match.is_some() && state.right_match.is_none()), new_state)
-            },
-            AddressRange(RegexPattern(s), Relative(count)) => {
-                match state.left_match {
-                    None if s.is_match(line) => (true, MatchState { left_match: Some(line_number), right_match: None }),
-                    None => (false, state.unchanged()),
-                    Some(last) if line_number > last + count => (false, MatchState { left_match: None, right_match: None }),  // reset
-                    Some(_) => (true, state.unchanged()),
-                }
+            Err(e) => {
+                // The files already written can still be undone
+                write_log(args, &undo_records)?;
+                return Err(e);
             },
-            AddressRange(RegexPattern(s), Step(count)) => todo!(),
-            _ => unreachable!("Shouldn't have branched into match_range"),
+            Ok(()) => {},
         }
     }
+    if let Some(mut out) = matched_out {
+        out.flush()?;
+    }
+    write_log(args, &undo_records)?;
+    if args.is_present("stats") {
+        // Each --script step has its own mode, so there's no single one to report
+        let mode = if args.is_present("script") {
+            "script".to_string()
+        } else {
+            effective_mode(args).to_string().to_lowercase()
+        };
+        emit(args, &format!("lines={} matched={} changed={} mode={}", stats.lines, stats.matched, stats.changed, mode));
+    }
+    if would_change > 0 {
+        return Err(Failure::WouldChange(would_change));
+    }
+    if failed > 0 {
+        return Err(Failure::FilesFailed(failed));
+    }
+    Ok(())
 }
 
-// --------------------------------
+/// Write the result for an INPUT file over `target`, returning what --log should record for it
+fn replace_input(args: &ArgMatches, file_path: &str, target: &Path, contents: &str, output: &[String], encoding: Option<&'static Encoding>) -> Result<Option<undo::UndoRecord>, ToggleError> {
+    let mut written = vec![];
+    write_output(&mut written, output, contents, args.is_present("ensure_trailing_newline"))?;
+    let written = String::from_utf8(written).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let record = if args.is_present("log") {
+        Some(undo::UndoRecord::new(file_path, contents, &written)?)
+    } else {
+        None
+    };
+    replace_file(target, &encode(written.into_bytes(), encoding)?).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file_path, e)))?;
+    Ok(record)
+}
 
-fn try_parse_component(s: &str) -> Result<AddressComponent, &str> {
-    if s.starts_with("/") {
-        let x = s.trim_start_matches("/").trim_end_matches("/");
-        return Ok(RegexPattern(Regex::new(x).unwrap()));
+/// Whether to skip an INPUT file that looks binary. Only runs over several files skip them, a
+/// single file is refused instead so that it isn't silently left out.
+fn skip_binary(args: &ArgMatches, file_path: &str, contents: &[u8], bulk: bool) -> Result<bool, ToggleError> {
+    if args.is_present("binary") || !is_binary(contents) {
+        return Ok(false);
+    }
+    if !bulk {
+        return Err(ToggleError::Usage(format!("{}: looks like a binary file, pass --binary to change it anyway", file_path)));
     }
-    if s.starts_with("+") {
-        return Ok(Relative(s.parse().map_err(|_| "unable to parse relative range")?));
-    } else if let Ok(x) = s.parse() {
-        return Ok(Line(x));
+    emit(args, &format!("toggle-comment: {}: skipping binary file", file_path));
+    Ok(true)
+}
+
+/// Replace one INPUT file with its result, as --in-place does
+fn edit_in_place(args: &ArgMatches, pattern_str: &str, file_path: &str, line_numbers: Option<&HashSet<usize>>, encoding: Option<&'static Encoding>, stats: &mut Stats, bulk: bool) -> Result<Option<undo::UndoRecord>, Failure> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", file_path, e));
+    let target = in_place_target(args, file_path).map_err(with_path)?;
+    let contents = read_input(file_path).map_err(with_path)?;
+    if skip_binary(args, file_path, &contents, bulk)? {
+        return Ok(None);
     }
-    Err("unable to parse component")
+    let contents = decode(contents, encoding).map_err(with_path)?;
+    let (output, _) = process(args, pattern_str, Some(file_path), &contents, line_numbers, stats, None::<io::Sink>)?;
+    Ok(replace_input(args, file_path, &target, &contents, &output, encoding)?)
 }
 
-fn try_parse_pattern(s: &str) -> Result<AddressPattern, &str> {
-    let negated = s.ends_with("!");
-    let s = s.trim_end_matches("!");
-    let parts: Vec<&str> = s.split(",").take(2).collect();
-    // FIXME: error on too many bits instead of ignore
-    // if parts.len() > 2 {
-    //     return Err("too many bits")
-    // }
-    let pattern = if parts.len() == 1 {
-        if parts[0].is_empty() {
-            Ok(AddressPattern::new_zero())
+/// Edit INPUT files in place on `threads` threads, adding up their stats. Each file is replaced
+/// through its own temporary file, so the workers share nothing but the list of files to take from.
+/// Returns how many files failed with --keep-going. The --log records of every file edited are
+/// added to `undo_records`, even when another file fails.
+#[allow(clippy::too_many_arguments)]
+fn edit_in_parallel(args: &ArgMatches, pattern_str: &str, inputs: &[&str], line_numbers: Option<&HashSet<usize>>, encoding: Option<&'static Encoding>, threads: usize, stats: &mut Stats, undo_records: &mut Vec<undo::UndoRecord>) -> Result<usize, Failure> {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(inputs.len())).map(|_| scope.spawn(|| {
+            let mut stats = Stats::default();
+            let mut failures = 0;
+            let mut records = vec![];
+            let mut error = None;
+            while let Some(file_path) = inputs.get(next.fetch_add(1, Ordering::Relaxed)).filter(|_| !failed.load(Ordering::Relaxed)) {
+                match edit_in_place(args, pattern_str, file_path, line_numbers, encoding, &mut stats, inputs.len() > 1) {
+                    Err(e) if args.is_present("keep_going") => {
+                        eprintln!("toggle-comment: {}", e);
+                        failures += 1;
+                    },
+                    Err(e) => {
+                        failed.store(true, Ordering::Relaxed);
+                        error = Some(e);
+                        break;
+                    },
+                    Ok(record) => records.extend(record),
+                }
+            }
+            (stats, failures, records, error)
+        })).collect();
+        workers.into_iter().map(|worker| worker.join().expect("worker thread panicked")).collect()
+    });
+    // Every worker's records are kept before the first error is returned
+    let mut failures = 0;
+    let mut first_error = None;
+    for (worker_stats, worker_failures, worker_records, error) in results {
+        stats.lines += worker_stats.lines;
+        stats.matched += worker_stats.matched;
+        stats.changed += worker_stats.changed;
+        failures += worker_failures;
+        undo_records.extend(worker_records);
+        first_error = first_error.or(error);
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(failures),
+    }
+}
+
+/// Write the --log file, if asked for, recording the files edited so far
+fn write_log(args: &ArgMatches, records: &[undo::UndoRecord]) -> io::Result<()> {
+    match args.value_of("log") {
+        Some(log) => fs::write(log, undo::log_to_json(records)),
+        None => Ok(()),
+    }
+}
+
+/// Restore the files recorded in a --log file
+fn undo(log: &str) -> Result<(), ToggleError> {
+    let contents = fs::read_to_string(log).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", log, e)))?;
+    for record in undo::log_from_json(&contents)? {
+        let current = fs::read_to_string(&record.path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", record.path, e)))?;
+        fs::write(&record.path, record.restore(&current)?)?;
+    }
+    Ok(())
+}
+
+/// Write the output lines, ending without a newline only when the input did. With `ensure_newline`
+/// trailing empty lines are dropped and the output always ends with exactly one newline.
+fn write_output<W: Write>(out: &mut W, output: &[String], contents: &str, ensure_newline: bool) -> io::Result<()> {
+    let final_newline = ensure_newline || contents.ends_with('\n');
+    let output = if ensure_newline {
+        let end = output.iter().rposition(|line| !line.is_empty()).map_or(0, |idx| idx + 1);
+        &output[..end]
+    } else {
+        output
+    };
+    for (idx, line) in output.iter().enumerate() {
+        if idx + 1 < output.len() || final_newline {
+            writeln!(out, "{}", line)?;
         } else {
-            Ok(AddressPattern::new_single(try_parse_component(parts[0])?))
+            write!(out, "{}", line)?;
         }
-    } else if parts.len() == 2 {
-        let (left, right) = (try_parse_component(parts[0])?, try_parse_component(parts[1])?);
-        Ok(AddressPattern::new_range(left, right))
-    } else {
-        Err("unimplemented")
+    }
+    Ok(())
+}
+
+/// The --encoding of the input and output, or None for UTF-8
+fn input_encoding(args: &ArgMatches) -> Result<Option<&'static Encoding>, ToggleError> {
+    let name = match args.value_of("encoding") {
+        Some(name) => name,
+        None => return Ok(None),
     };
-    if negated {
-        pattern.map(|p| p.invert())
-    } else {
-        pattern
+    match Encoding::for_label(name.as_bytes()) {
+        Some(encoding) if encoding == encoding_rs::UTF_8 => Ok(None),
+        // Decoding only encodings like UTF-16 can't be written back out
+        Some(encoding) if encoding.output_encoding() == encoding => Ok(Some(encoding)),
+        Some(_) => Err(ToggleError::Usage(format!("encoding {:?} can be read but not written", name))),
+        None => Err(ToggleError::Usage(format!("unknown encoding {:?}", name))),
     }
 }
 
-arg_enum! {
-    enum CommentingMode {
-        Toggle,
-        Comment,
-        Uncomment,
+/// Decode input bytes, failing rather than substituting for anything malformed
+fn decode(bytes: Vec<u8>, encoding: Option<&'static Encoding>) -> io::Result<String> {
+    match encoding {
+        None => String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Some(encoding) => encoding.decode_without_bom_handling_and_without_replacement(&bytes)
+            .map(|text| text.into_owned())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("stream did not contain valid {}", encoding.name()))),
     }
 }
 
-fn force_comment_line(_prefix_pattern: &Regex, prefix: &str, line: &str) -> String {
-    format!("{}{}", prefix, line)
+/// Encode UTF-8 output bytes, failing on characters the encoding can't represent
+fn encode(bytes: Vec<u8>, encoding: Option<&'static Encoding>) -> io::Result<Vec<u8>> {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return Ok(bytes),
+    };
+    let text = String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match encoding.encode(&text) {
+        (_, _, true) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("output can't be written as {}", encoding.name()))),
+        (encoded, _, false) => Ok(encoded.into_owned()),
+    }
 }
 
-fn comment_line(prefix_pattern: &Regex, prefix: &str, line: &str) -> String {
-    if !prefix_pattern.is_match(line) {
-        format!("{}{}", prefix, line)
-    } else {
-        line.to_string()
+/// `write_output` in the --encoding, if any
+fn write_encoded<W: Write>(out: &mut W, output: &[String], contents: &str, ensure_newline: bool, encoding: Option<&'static Encoding>) -> io::Result<()> {
+    if encoding.is_none() {
+        return write_output(out, output, contents, ensure_newline);
     }
+    let mut written = vec![];
+    write_output(&mut written, output, contents, ensure_newline)?;
+    out.write_all(&encode(written, encoding)?)
 }
 
-fn toggle_line(prefix_pattern: &Regex, prefix: &str, line: &str) -> String {
-    if prefix_pattern.is_match(line) {
-        prefix_pattern.replace(line, "$head$tail").to_string()
-    } else {
-        format!("{}{}", prefix, line)
+/// The file --in-place should replace for an INPUT: for a symlink, the file it points to so that
+/// the link itself survives, unless --no-follow-symlinks asks to refuse symlinks instead
+fn in_place_target(args: &ArgMatches, file_path: &str) -> io::Result<PathBuf> {
+    if !fs::symlink_metadata(file_path)?.file_type().is_symlink() {
+        return Ok(PathBuf::from(file_path));
+    }
+    if args.is_present("no_follow_symlinks") {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "is a symlink, not editing it with --no-follow-symlinks"));
     }
+    fs::canonicalize(file_path)
 }
 
-fn uncomment_line(prefix_pattern: &Regex, _prefix: &str, line: &str) -> String {
-    prefix_pattern.replace(line, "$head$tail").to_string()
+/// Write a temporary file beside `path` and rename it over the original, so that nothing ever sees
+/// a partly written file. The original's permissions are kept.
+fn replace_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let mut temp_name = OsString::from(".");
+    temp_name.push(path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?);
+    temp_name.push(format!(".toggle-comment-{}", std::process::id()));
+    let temp = dir.join(temp_name);
+    let result = fs::write(&temp, contents)
+        .and_then(|_| fs::set_permissions(&temp, fs::metadata(path)?.permissions()))
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
 }
 
-fn comment_lines(lines: Lines, pattern: &AddressPattern, prefix: &str, mode: &CommentingMode) -> Vec<String> {
-    let prefix_pattern: Regex = Regex::new(&format!(r"^(?P<head>\s*){}(?P<tail>.*?)$", prefix)).unwrap();
-    let operator = match mode {
-        CommentingMode::Comment => comment_line,
-        CommentingMode::Toggle => toggle_line,
-        CommentingMode::Uncomment => uncomment_line,
-    };
+/// Print the changes as a JSON array of LSP text edits, for editors to apply
+fn print_edits(contents: &str, output: &[String]) -> Result<(), ToggleError> {
+    let before: Vec<&str> = contents.lines().collect();
+    if before.len() != output.len() {
+        return Err(ToggleError::Usage("--lsp-edits can't describe added or removed lines".to_string()));
+    }
+    println!("{}", edits_to_json(&line_edits(&before, output)));
+    Ok(())
+}
 
-    let mut output = vec![];
-    for (idx, line) in lines.enumerate() {
-        let line_number = idx + 1;
-        // XXX: shouldn't be tracking MatchState since we are not in block-commenting?
-        if pattern.matches(line_number, line, &EMPTY_STATE).0 {
-            output.push(format!("{}", operator(&prefix_pattern, prefix, line)));
-        } else {
-            output.push(format!("{}", line));
-        }
+/// Print an ed script making the changes, to be run as `ed -s FILE`
+fn print_ed_script(contents: &str, output: &[String]) -> Result<(), ToggleError> {
+    let before: Vec<&str> = contents.lines().collect();
+    if before.len() != output.len() {
+        return Err(ToggleError::Usage("--ed-script can't describe added or removed lines".to_string()));
     }
-    return output;
+    print!("{}", ed_script(&before, output));
+    Ok(())
 }
 
-fn get_matches<'a>(pattern: &AddressPattern, lines: &Vec<&'a str>, initial_state: MatchState) -> Vec<(bool, Vec<&'a str>)> {
-    let mut i = lines.iter().enumerate()
-        .scan(initial_state, |state, (idx, &l)| {
-            let (is_match, new_state) = pattern.matches(idx+1, l, &state);
-            state.update(new_state);
-            Some((is_match, l))
-        })
-        .peekable();
+/// Print the direction each matched block was toggled in and its resulting lines, as JSON
+fn print_plan(contents: &str, output: &[String], plan: &[BlockPlan]) -> Result<(), ToggleError> {
+    if contents.lines().count() != output.len() {
+        return Err(ToggleError::Usage("--emit-plan can't describe added or removed lines".to_string()));
+    }
+    println!("{}", plan_to_json(plan, output));
+    Ok(())
+}
 
-    let mut retval = vec![];
-    while let Some((last, l)) = i.next() {
-        let mut v: Vec<&str> = vec![l];
-        while let Some(&(matched, l)) = i.peek() {
-            if matched != last {
-                break;
-            }
-            v.push(l);
-            i.next();
+/// Print each line --check found would change as `name:N` followed by `-old` and `+new` lines,
+/// shortened to `preview_width` if given, returning how many there were
+fn report_changes(name: &str, contents: &str, output: &[String], preview_width: Option<usize>) -> usize {
+    let shown = |line: &str| match preview_width {
+        Some(width) => truncate_preview(line, width),
+        None => line.to_string(),
+    };
+    let before: Vec<&str> = contents.lines().collect();
+    let mut changed = 0;
+    for idx in 0..before.len().max(output.len()) {
+        let (old, new) = (before.get(idx).copied(), output.get(idx).map(String::as_str));
+        if old != new {
+            changed += 1;
+            println!("{}:{}", name, idx + 1);
+            old.into_iter().for_each(|l| println!("-{}", shown(l)));
+            new.into_iter().for_each(|l| println!("+{}", shown(l)));
         }
-        retval.push((last, v));
     }
-    retval
+    changed
 }
 
-fn will_comment<S: AsRef<str>>(prefix_pattern: &Regex, lines: &Vec<S>) -> bool {
-    let blank = Regex::new(r"^\s*$").unwrap();
-    // Walk once to determine if all-nonblank lines are commented or not
-    for line in lines.iter() {
-        let line = line.as_ref();
-        if blank.is_match(line) {
-            continue;
-        } else if !prefix_pattern.is_match(line) {
-            // Line does not match comment pattern, so we should comment out the whole block
-            return true;
-        }
+fn regex_size_limit(args: &ArgMatches) -> usize {
+    if args.is_present("max_regex_size") {
+        value_t!(args.value_of("max_regex_size"), usize).unwrap_or_else(|e| e.exit())
+    } else {
+        DEFAULT_REGEX_SIZE_LIMIT
     }
-    return false;
 }
 
-fn comment_block<S: AsRef<str>>(mode: &CommentingMode, prefix: &str, lines: &Vec<S>) -> Vec<String> {
-    let prefix_pattern: Regex = Regex::new(&format!(r"^(?P<head>\s*){}(?P<tail>.*?)$", prefix)).unwrap();
-    let blank = Regex::new(r"^\s*$").unwrap();
-    let operator: fn(&Regex, &str, &str) -> String = match mode {
-        CommentingMode::Comment => comment_line,
-        CommentingMode::Uncomment => uncomment_line,
-        CommentingMode::Toggle if will_comment(&prefix_pattern, lines) => force_comment_line,
-        CommentingMode::Toggle => uncomment_line,  // otherwise
+/// Parse the address pattern along with the options that reshape it, before any input is seen
+fn build_pattern(args: &ArgMatches, pattern_str: &str) -> Result<AddressPattern, ToggleError> {
+    let size_limit = regex_size_limit(args);
+    let separator = match args.value_of("addr_sep") {
+        Some(sep) => try_parse_separator(sep)?,
+        None => ',',
+    };
+    let pattern = match args.value_of("hunk") {
+        Some(header) => try_parse_hunk(header)?,
+        None => try_parse_pattern_with(pattern_str, size_limit, separator)?,
     };
-    let mut output = vec![];
+    let pattern = if let Some(delimiters) = args.values_of("balanced") {
+        let delimiters: Vec<char> = delimiters.map(try_parse_delimiter).collect::<Result<_, _>>()?;
+        pattern.balanced(delimiters[0], delimiters[1])?
+    } else if args.is_present("indent_block") {
+        pattern.indent_block()?
+    } else if args.is_present("join") {
+        pattern.joined(value_t!(args.value_of("join"), usize).unwrap_or_else(|e| e.exit()))?
+    } else {
+        pattern
+    };
+    Ok(if args.is_present("inverse_block") { pattern.invert() } else { pattern })
+}
 
-    for line in lines.iter() {
-        let line = line.as_ref();
-        if blank.is_match(line) {
-            output.push(line.to_string());
-            continue;
+fn try_parse_separator(s: &str) -> Result<char, ToggleError> {
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if !matches!(c, '/' | '!' | '+' | '-') && !c.is_ascii_digit() => Ok(c),
+        _ => Err(ToggleError::Usage(format!("address separator must be a single character that can't start an address, got {:?}", s))),
+    }
+}
+
+/// Print a warning or summary to stderr, unless --quiet. Errors are reported by main regardless.
+fn emit(args: &ArgMatches, message: &str) {
+    if !args.is_present("quiet") {
+        eprintln!("{}", message);
+    }
+}
+
+fn warn_if_inverted(args: &ArgMatches, pattern: &AddressPattern) {
+    if args.is_present("verbose") && pattern.is_inverted() {
+        emit(args, "toggle-comment: warning: range ends before it starts, no lines will match");
+    }
+}
+
+/// Look up the --prefix-map entry for the file's extension
+fn mapped_prefix(args: &ArgMatches, path: &Path) -> Result<Option<String>, ToggleError> {
+    let ext = match path.extension().and_then(OsStr::to_str) {
+        Some(ext) => ext,
+        None => return Ok(None),
+    };
+    for mapping in args.values_of("prefix_map").into_iter().flatten() {
+        let (mapped_ext, prefix) = try_parse_prefix_mapping(mapping)?;
+        if mapped_ext == ext {
+            return Ok(Some(prefix));
         }
-        output.push(operator(&prefix_pattern, prefix, line));
     }
-    return output;
+    Ok(None)
 }
 
-fn get_bin_name() -> OsString {
-    let args: Vec<OsString> = std::env::args_os().collect();
-    let p = Path::new(OsStr::new(&args[0]));
-    p.file_name().unwrap_or(OsStr::new("<UNSET>")).into()
+fn marker_style(args: &ArgMatches) -> Option<MarkerStyle> {
+    args.value_of("marker_style").map(|style| style.parse().unwrap())
 }
 
-fn body(contents: Lines, initial_state: MatchState, pattern: &AddressPattern, prefix: &str, mode: &CommentingMode) -> Vec<String> {
-    let mut retval: Vec<String> = vec![];
-    if pattern.is_range() {
-        // TODO: don't collect all these lines
-        for (is_match, chunk) in get_matches(&pattern, &contents.collect(), initial_state) {
-            if is_match {
-                retval.extend(comment_block(&mode, &prefix, &chunk));
-            } else {
-                retval.extend(chunk.iter().map(|s| s.to_string()));
-            }
+/// Pick the comment prefix, falling back to .editorconfig or a guess from the input when neither
+/// -c nor --marker-style is given
+fn resolve_prefix(args: &ArgMatches, detected: Option<String>) -> Result<String, ToggleError> {
+    let prefix = args.value_of("comment_prefix").map(str::to_string)
+        .or_else(|| marker_style(args).map(|style| style.prefix().to_string()))
+        .or(detected)
+        .unwrap_or_else(|| "# ".to_string());
+    if args.is_present("repeat_marker") {
+        let count = value_t!(args.value_of("repeat_marker"), usize).unwrap_or_else(|e| e.exit());
+        if count == 0 {
+            return Err(ToggleError::Usage("--repeat-marker must be at least 1".to_string()));
         }
+        Ok(repeat_marker(&prefix, count))
     } else {
-        retval.extend(comment_lines(contents, &pattern, prefix, &mode));
+        Ok(prefix)
     }
-    retval
 }
 
-fn main() {
-    // Check options, do we have a pattern? A filename? A target state?
-    // Open streams
-    // Guess language if not specified
-    // Match lines and set/toggle comment status
-    let default_mode = match get_bin_name().to_str() {
-        Some("comment") => "comment",
-        Some("uncomment") => "uncomment",
-        _ => "toggle",
-    };
+/// The mode that --mode gives, unless --reindent, --enable or --disable decides it instead
+fn effective_mode(args: &ArgMatches) -> CommentingMode {
+    if args.is_present("reindent") || args.is_present("enable") {
+        CommentingMode::Uncomment
+    } else if args.is_present("disable") {
+        CommentingMode::Comment
+    } else {
+        value_t!(args.value_of("comment_mode"), CommentingMode).unwrap()
+    }
+}
 
-    let args = App::new("toggle-comment")
-        .version(crate_version!())
-        .about("A utility for setting or toggling the line-comment status of lines in text files")
-        .arg(Arg::with_name("comment_mode")
-            .long("mode")
-            .value_name("comment|toggle|uncomment")
-            .help(&format!("Commenting behaviour [default: {}]", default_mode))
-            .default_value(default_mode)
-            .hide_default_value(true)
-            .possible_values(&["comment", "toggle", "uncomment"])
-            .case_insensitive(true)
-            .hide_possible_values(true))
-        .arg(Arg::with_name("comment_prefix")
-            .value_name("PREFIX")
-            .short("c")
-            .long("comment-prefix")
-            .takes_value(true)
-            .help("Line comment prefix string [default: \"# \"]"))
-        .arg(Arg::with_name("PATTERN")
-            .help("ed-like address pattern for selecting lines.")
-            .required(true))
-        .arg(Arg::with_name("INPUT")
-            .help("Sets the input file."))
-        .get_matches();
-
-    let mode = value_t!(args.value_of("comment_mode"), CommentingMode).unwrap();
-    let pattern_str = args.value_of("PATTERN").unwrap_or("");
-    let pattern = try_parse_pattern(pattern_str).expect("Unable to parse pattern");
-    let contents = if let Some(file_path) = args.value_of("INPUT") {
-        fs::read_to_string(file_path).expect("Unable to read file")  // TODO: edit this input file in place
+fn build_options<'a>(args: &'a ArgMatches, prefix: &'a str) -> Result<Options<'a>, ToggleError> {
+    let reindent = if args.is_present("reindent") {
+        Some(value_t!(args.value_of("reindent"), usize).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    let mode = effective_mode(args);
+    let surround = Surround { before: args.value_of("prepend"), after: args.value_of("append") };
+    let block_delimiters = marker_style(args).and_then(MarkerStyle::delimiters);
+    let skip_marker = args.value_of("skip_marker").map(Regex::new).transpose()?;
+    let shape = match args.value_of("match_shape") {
+        Some(shape) => {
+            let (shape, negated) = match shape.strip_prefix('!') {
+                Some(shape) => (shape, true),
+                None => (shape, false),
+            };
+            let shape = shape.parse::<LineShape>().map_err(|_| ToggleError::Usage(format!("unknown line shape {:?}", shape)))?;
+            Some((shape, negated))
+        },
+        None => None,
+    };
+    let tabstop = if args.is_present("tabstop") {
+        value_t!(args.value_of("tabstop"), usize).unwrap_or_else(|e| e.exit())
+    } else {
+        8
+    };
+    let retab = if args.is_present("expand_tabs") {
+        Some(Retab::Expand(tabstop))
+    } else if args.is_present("unexpand_tabs") {
+        Some(Retab::Unexpand(tabstop))
     } else {
-        let mut buffer = String::new();
-        io::stdin().read_to_string(&mut buffer).expect("Unable to read from stdin");
-        buffer
+        None
+    };
+    let space_boundary = args.is_present("space_boundary");
+    let prefix_rules = args.values_of("prefix_rule").into_iter().flatten().map(try_parse_prefix_rule).collect::<Result<_, _>>()?;
+    let and_regexes = args.values_of("and_regex").into_iter().flatten().map(Regex::new).collect::<Result<_, _>>()?;
+    let protect = args.values_of("protect_regex").into_iter().flatten().map(Regex::new).collect::<Result<_, _>>()?;
+    let requires = args.value_of("requires").map(|re| try_parse_regex(re, regex_size_limit(args))).transpose()?;
+    let number_comments = args.is_present("number_comments");
+    let single_block = args.is_present("inverse_block") || args.is_present("global_toggle");
+    let comment_blank = args.is_present("comment_blank");
+    let boxed = args.is_present("box");
+    let force = args.is_present("force");
+    let ascii = args.is_present("ascii");
+    let duplicates = args.is_present("comment_dupes");
+    let first_per_block = args.is_present("first_per_block");
+    let strip_trailing = args.is_present("strip_trailing");
+    let tag_line_number = args.is_present("tag_line_number");
+    let rejoin = args.is_present("rejoin");
+    let sort = args.is_present("sort");
+    let mark_whitespace = args.is_present("mark_whitespace");
+    let adopt_prefix = args.is_present("adopt_prefix");
+    let literal_uncomment = args.is_present("literal_uncomment");
+    let escape_existing = args.is_present("escape_existing");
+    let logical_lines = args.is_present("logical_lines");
+    let remove_all = args.is_present("remove_all");
+    let pad_to_width = args.is_present("pad_to_width");
+    let time_window = if args.is_present("before") || args.is_present("after") {
+        Some(TimeWindow::new(args.value_of("time_format").unwrap(), args.value_of("before"), args.value_of("after"))?)
+    } else {
+        None
+    };
+    let sentinels = args.is_present("sentinels");
+    let record_disabled = args.is_present("disable") || args.is_present("enable");
+    let region = args.value_of("region").map(region_markers);
+    let any_of = args.value_of("any_of").map(try_parse_any_of).transpose()?.unwrap_or_default();
+    let stripe = if args.is_present("stripe") {
+        let step = value_t!(args.value_of("stripe"), usize).unwrap_or_else(|e| e.exit());
+        if step == 0 {
+            return Err(ToggleError::Usage("--stripe must be at least 1".to_string()));
+        }
+        let offset = if args.is_present("stripe_offset") {
+            value_t!(args.value_of("stripe_offset"), usize).unwrap_or_else(|e| e.exit())
+        } else {
+            1
+        };
+        Some((step, offset))
+    } else {
+        None
+    };
+    let empty_comment_to_blank = args.is_present("empty_comment_to_blank");
+    let occurrence = if args.is_present("occurrence") {
+        let occurrence = value_t!(args.value_of("occurrence"), usize).unwrap_or_else(|e| e.exit());
+        if occurrence == 0 {
+            return Err(ToggleError::Usage("--occurrence counts from 1".to_string()));
+        }
+        Some(occurrence)
+    } else {
+        None
+    };
+    let nth = if args.is_present("nth") {
+        let nth = value_t!(args.value_of("nth"), usize).unwrap_or_else(|e| e.exit());
+        if nth == 0 {
+            return Err(ToggleError::Usage("--nth counts from 1".to_string()));
+        }
+        Some(nth)
+    } else {
+        None
+    };
+    let indent_char = value_t!(args.value_of("indent_char"), IndentChar).unwrap_or_else(|e| e.exit());
+    let baseline = args.value_of("baseline").map(|path| {
+        fs::read_to_string(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+    }).transpose()?;
+    Ok(Options {
+        surround,
+        skip_marker,
+        requires,
+        number_comments,
+        single_block,
+        comment_blank,
+        boxed,
+        force,
+        ascii,
+        duplicates,
+        reindent,
+        first_per_block,
+        strip_trailing,
+        baseline,
+        protect,
+        shape,
+        retab,
+        space_boundary,
+        and_regexes,
+        tag_line_number,
+        block_delimiters,
+        rejoin,
+        indent_char,
+        occurrence,
+        nth,
+        empty_comment_to_blank,
+        sort,
+        region,
+        stripe,
+        any_of,
+        mark_whitespace,
+        adopt_prefix,
+        record_disabled,
+        prefix_rules,
+        literal_uncomment,
+        escape_existing,
+        logical_lines,
+        remove_all,
+        pad_to_width,
+        sentinels,
+        time_window,
+        ..Options::new(prefix, mode)
+    })
+}
+
+/// Write the original matched lines, optionally separating blocks with a gap between them
+fn write_matched<W: Write>(out: &mut W, chunks: &[(bool, Vec<&str>)], separate: bool) -> io::Result<()> {
+    let mut line = 0;
+    let mut last_end = None;
+    for (is_match, chunk) in chunks {
+        if *is_match {
+            if separate && last_end.is_some_and(|end| end != line) {
+                writeln!(out, "--")?;
+            }
+            for text in chunk {
+                writeln!(out, "{}", text)?;
+            }
+            last_end = Some(line + chunk.len());
+        }
+        line += chunk.len();
+    }
+    Ok(())
+}
+
+/// Run `command` through the shell with `contents` on its stdin, selecting the `N:` lines it prints.
+/// Exit status 1 means nothing matched, as with grep; any other failure is an error.
+fn filter_lines(command: &str, contents: &str) -> Result<HashSet<usize>, ToggleError> {
+    use std::process::{Command, Stdio};
+
+    let failed = |reason: String| ToggleError::Io(io::Error::other(format!("filter command {:?} failed: {}", command, reason)));
+    let mut child = Command::new("sh").arg("-c").arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed(e.to_string()))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = contents.to_string();
+    // Feed the input from another thread so a command writing as it reads can't fill its stdout and stall
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().map_err(|e| failed(e.to_string()))?;
+    // The command may exit without reading all of its input, which is fine
+    let _ = writer.join();
+    match output.status.code() {
+        Some(0) => try_parse_numbered_lines(&String::from_utf8_lossy(&output.stdout)),
+        Some(1) => Ok(HashSet::new()),
+        _ => Err(failed(output.status.to_string())),
+    }
+}
+
+/// The lines of `path` changed since the last commit, staged or not, from `git diff -U0 HEAD`
+fn git_modified_lines(path: &Path) -> Result<HashSet<usize>, ToggleError> {
+    use std::process::{Command, Stdio};
+
+    let failed = |reason: String| ToggleError::Io(io::Error::other(format!("{}: git diff failed: {}", path.display(), reason)));
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    let name = path.file_name().ok_or_else(|| failed("not a file".to_string()))?;
+    let output = Command::new("git")
+        .arg("-C").arg(dir)
+        .args(["diff", "-U0", "--no-color", "--no-ext-diff", "HEAD", "--"])
+        .arg(name)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| failed(e.to_string()))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(failed(stderr.lines().next().unwrap_or("").to_string()));
+    }
+    try_parse_diff_lines(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Transform the contents of a single input, line numbers and -N addresses are relative to it
+fn process<W: Write>(args: &ArgMatches, pattern_str: &str, file_path: Option<&str>, contents: &str, line_numbers: Option<&HashSet<usize>>, stats: &mut Stats, mut matched_out: Option<W>) -> Result<(Vec<String>, Vec<BlockPlan>), Failure> {
+    let mapped = match file_path {
+        Some(path) => mapped_prefix(args, Path::new(path))?,
+        None => None,
+    };
+    let configured = mapped.or_else(|| file_path.and_then(|path| editorconfig::comment_prefix(Path::new(path))));
+    let prefix = resolve_prefix(args, configured.or_else(|| detect_prefix(contents)))?;
+    if let (Some(explicit), Some(marker)) = (args.value_of("comment_prefix"), shebang_marker(contents)) {
+        if explicit.trim() != marker {
+            emit(args, &format!("toggle-comment: warning: {}: prefix {:?} may be wrong, the #! line suggests {:?}", file_path.unwrap_or("<stdin>"), explicit, marker));
+        }
+    }
+    let mut options = build_options(args, &prefix)?;
+    options.line_numbers = line_numbers.cloned();
+    if let Some(range) = args.value_of("byte_range") {
+        let (start, end) = try_parse_byte_range(range)?;
+        if start >= contents.len() {
+            return Err(ToggleError::Usage(format!("--byte-range starts at byte {}, past the end of the {}-byte input", start, contents.len())).into());
+        }
+        options.line_numbers = Some(byte_range_lines(contents, start, end));
+    }
+    if let Some(command) = args.value_of("filter_cmd") {
+        options.line_numbers = Some(filter_lines(command, contents)?);
+    }
+    if args.is_present("git_modified") {
+        let path = file_path.ok_or_else(|| ToggleError::Usage("--git-modified needs INPUT files, not stdin".to_string()))?;
+        options.line_numbers = Some(git_modified_lines(Path::new(path))?);
+    }
+    if let (Some(name), Some((begin, end))) = (args.value_of("region"), &options.region) {
+        let lines: Vec<&str> = contents.lines().collect();
+        if let (_, Some(line)) = region_lines(&lines, begin, end) {
+            emit(args, &format!("toggle-comment: warning: {}:{}: region {:?} is never closed, leaving it alone", file_path.unwrap_or("<stdin>"), line, name));
+        }
+    }
+    let steps = match args.value_of("script") {
+        Some(script) => try_parse_script(script)?.into_iter().map(|(pattern, mode)| (pattern, Some(mode))).collect(),
+        None => vec![(pattern_str, None)],
     };
-    let prefix = args.value_of("comment_prefix").unwrap_or("# ");
-    let initial_state = EMPTY_STATE.unchanged();
 
-    for line in body(contents.lines(), initial_state, &pattern, prefix, &mode) {
-        println!("{}", line);
+    // Each --script step sees the output of the one before
+    let mut output: Vec<String> = contents.lines().map(str::to_string).collect();
+    let mut plan = vec![];
+    for (idx, (step_pattern, mode)) in steps.into_iter().enumerate() {
+        let mut pattern = build_pattern(args, step_pattern)?;
+        pattern.resolve_from_end(output.len());
+        warn_if_inverted(args, &pattern);
+        if let Some(mode) = mode {
+            options.mode = mode;
+        }
+        let lines: Vec<&str> = output.iter().map(String::as_str).collect();
+        let chunks = select(&lines, EMPTY_STATE.unchanged(), &pattern, &options);
+        if args.is_present("warn_mixed") {
+            for (line, marker) in mixed_markers(&chunks, options.prefix) {
+                emit(args, &format!("toggle-comment: warning: {}:{}: block mixes {} comments with the prefix {:?}", file_path.unwrap_or("<stdin>"), line, marker, options.prefix));
+            }
+        }
+        if args.is_present("emit_plan") {
+            plan = plan_blocks(&chunks, &pattern, &options);
+        }
+        if let Some(out) = matched_out.as_mut() {
+            write_matched(out, &chunks, args.is_present("context_separator"))?;
+        }
+        let mut step_stats = Stats::default();
+        let step_output = render(chunks, &pattern, &options, &mut step_stats);
+        // Count the input's lines once, however many steps pass over them
+        stats.lines += if idx == 0 { step_stats.lines } else { 0 };
+        stats.matched += step_stats.matched;
+        stats.changed += step_stats.changed;
+        output = step_output;
+    }
+    if args.is_present("squeeze") {
+        output = squeeze_blank_runs(output);
+    }
+    if args.is_present("max_width") {
+        let max_width = value_t!(args.value_of("max_width"), usize).unwrap_or_else(|e| e.exit());
+        let before: Vec<&str> = contents.lines().collect();
+        let widened = widened_lines(&before, &output, max_width);
+        for &(line, width) in &widened {
+            emit(args, &format!("toggle-comment: warning: {}:{}: line is {} characters wide, over --max-width {}", file_path.unwrap_or("<stdin>"), line, width, max_width));
+        }
+        if args.is_present("strict_width") && !widened.is_empty() {
+            return Err(Failure::TooWide(widened.len()));
+        }
     }
+    Ok((output, plan))
 }
 
-#[cfg(test)]
-mod test;
+/// Rewrite stdin a line at a time, flushing each line as soon as it's written
+fn stream(args: &ArgMatches, pattern_str: &str, stats: &mut Stats) -> Result<(), ToggleError> {
+    let pattern = build_pattern(args, pattern_str)?;
+    warn_if_inverted(args, &pattern);
+    // There's nothing to guess the prefix from before the first line arrives
+    let prefix = resolve_prefix(args, None)?;
+    let options = build_options(args, &prefix)?;
+    if !options.is_streamable(&pattern) {
+        return Err(ToggleError::Usage("--line-buffered needs a pattern and options that never look ahead".to_string()));
+    }
+    let mut lines = LineStream::new(&pattern, &options);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in io::stdin().lock().lines() {
+        lines.write_line(&mut out, &line?, stats)?;
+        out.flush()?;
+    }
+    Ok(())
+}
@@ -7,7 +7,7 @@ macro_rules! matchtest {
         fn $name() {
             let prefix = "# ";
             let prefix_pattern= Regex::new(&format!(r"^(?P<head>\s*){}(?P<tail>.*?)$", prefix)).unwrap();
-            assert_eq!($fun(&prefix_pattern, prefix, $given), $expected);
+            assert_eq!($fun(&prefix_pattern, prefix, $given).to_string(), $expected);
         }
     };
 }
@@ -34,7 +34,7 @@ fn toggle_initial_uncomment() {
         "# #c = 3",
         "# d = 4",
     ];
-    let actual = comment_block(&CommentingMode::Toggle, "# ", &example);
+    let actual = toggle_block(&PREFIX, "# ", &example);
     assert_eq!(actual, expected);
 }
 
@@ -52,7 +52,7 @@ fn toggle_initial_comment() {
         "# # c = 3",
         "# d = 4",
     ];
-    let actual = comment_block(&CommentingMode::Toggle, "# ", &example);
+    let actual = toggle_block(&PREFIX, "# ", &example);
     assert_eq!(actual, expected);
 }
 
@@ -70,7 +70,7 @@ fn toggle_comment_initial_blank() {
         "#         # NOTE: choose better names",
         "#         return bar",
     ];
-    let actual = comment_block(&CommentingMode::Toggle, "# ", &example);
+    let actual = toggle_block(&PREFIX, "# ", &example);
     assert_eq!(actual, expected);
 }
 
@@ -122,6 +122,7 @@ fn regex_range_address_matches_block() {
 
 lazy_static! {
     static ref PREFIX: Regex = Regex::new(r"^(?P<head>\s*)# (?P<tail>.*?)$").unwrap();
+    static ref BLANK: Regex = Regex::new(r"^\s*$").unwrap();
 }
 
 #[test]
@@ -130,7 +131,7 @@ fn not_all_lines_commented_should_comment() {
         "# not all lines commented should comment",
         "abc = 123",
     ];
-    assert!(will_comment(&PREFIX, &example));
+    assert!(will_comment(&PREFIX, &BLANK, &example));
 }
 
 #[test]
@@ -139,7 +140,7 @@ fn all_lines_commented_should_uncomment() {
         "# all lines commented should uncomment",
         "# abc = 123",
     ];
-    assert!(!will_comment(&PREFIX, &example));
+    assert!(!will_comment(&PREFIX, &BLANK, &example));
 }
 
 #[test]
@@ -148,12 +149,12 @@ fn blanks_do_not_affect_will_comment() {
         "all lines uncommented or blank should comment",
         "",
     ];
-    assert!(will_comment(&PREFIX, &example1));
+    assert!(will_comment(&PREFIX, &BLANK, &example1));
     let example2 = vec![
         "# all lines commented or blank should uncomment",
         "",
     ];
-    assert!(!will_comment(&PREFIX, &example2));
+    assert!(!will_comment(&PREFIX, &BLANK, &example2));
 }
 
 
@@ -163,7 +164,7 @@ fn all_blank_lines_are_unchanged() {
         "",
         "",
     ];
-    assert!(!will_comment(&PREFIX, &expected));
+    assert!(!will_comment(&PREFIX, &BLANK, &expected));
 
     let prefix = "# ";
     let actual = comment_block(&CommentingMode::Toggle, prefix, &expected);
@@ -180,14 +181,14 @@ fn round_trip() {
         "# # not all lines commented",
         "# abc = 123",
     ];
-    let actual = comment_block(&CommentingMode::Toggle, "# ", &example);
+    let actual = toggle_block(&PREFIX, "# ", &example);
     assert_eq!(actual, expected);
-    assert_eq!(comment_block(&CommentingMode::Toggle, "# ", &actual), example);
+    assert_eq!(toggle_block(&PREFIX, "# ", &actual), example);
 }
 
 #[test]
 fn zero_address_toggles_whole_file_not_individual_lines() {
-    let example = vec![
+    let example = [
         "a = 1",
         "# b = 2",
         "c = 3",
@@ -198,14 +199,14 @@ fn zero_address_toggles_whole_file_not_individual_lines() {
         "# c = 3",
     ];
     let pattern = AddressPattern::new_zero();
-    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, "# ", &CommentingMode::Toggle);
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Toggle));
     assert_eq!(actual, expected);
 }
 
-use {Address::AddressRange, AddressComponent::*};
+use Address::AddressRange;
 macro_rules! address_range {
-    ($range:expr) => { AddressPattern { pattern: $range, negated: false }; };
-    ($range:expr, $negated:expr) => { AddressPattern { pattern: $range, negated: $negated }; };
+    ($range:expr) => { AddressPattern { pattern: $range, negated: false } };
+    ($range:expr, $negated:expr) => { AddressPattern { pattern: $range, negated: $negated } };
 }
 
 macro_rules! assert_matches_lines { ($addr:expr, $( $l:expr ),*) => { $( assert!($addr.matches($l, "", &EMPTY_STATE).0); )* }; }
@@ -272,7 +273,7 @@ fn matches_regex_relative_range() {
     let addr = address_range!(AddressRange(RegexPattern(re), Relative(3)));
 
     assert!( addr.matches(1, "foo", &EMPTY_STATE).0);
-    let state = MatchState { left_match: Some(1), right_match: None };
+    let state = MatchState { left_match: Some(1), right_match: None, depth: 0 };
     assert!( addr.matches(2, "match", &state).0);
     assert!( addr.matches(3, "match", &state).0);
     assert!( addr.matches(4, "match", &state).0);
@@ -285,7 +286,7 @@ fn matches_regex_absolute_range() {
     let addr = address_range!(AddressRange(RegexPattern(re), Line(4)));
 
     assert!( addr.matches(1, "foo", &EMPTY_STATE).0);
-    let state = MatchState { left_match: Some(1), right_match: None };
+    let state = MatchState { left_match: Some(1), right_match: None, depth: 0 };
     assert!( addr.matches(2, "match", &state).0);
     assert!( addr.matches(3, "match", &state).0);
     assert!( addr.matches(4, "match", &state).0);
@@ -300,7 +301,7 @@ fn matches_regex_empty_absolute_range() {
     assert!(!addr.matches(1, "un-match", &EMPTY_STATE).0);
     assert!(!addr.matches(2, "un-match", &EMPTY_STATE).0);
     assert!( addr.matches(3, "foo", &EMPTY_STATE).0);
-    let state = MatchState { left_match: Some(3), right_match: None };
+    let state = MatchState { left_match: Some(3), right_match: None, depth: 0 };
     assert!(!addr.matches(4, "un-match", &state).0);
     assert!(!addr.matches(5, "un-match", &state).0);
 }
@@ -314,7 +315,7 @@ fn matches_absolute_regex_end_range() {
     assert!( addr.matches(2, "match", &EMPTY_STATE).0);
     assert!( addr.matches(3, "match", &EMPTY_STATE).0);
     assert!( addr.matches(4, "foo", &EMPTY_STATE).0);
-    let state = MatchState { left_match: None, right_match: Some(4) };
+    let state = MatchState { left_match: None, right_match: Some(4), depth: 0 };
     assert!(!addr.matches(5, "un-match", &state).0);
 }
 
@@ -326,10 +327,10 @@ fn matches_double_regex_range() {
 
     assert!(!addr.matches(1, "un-match", &EMPTY_STATE).0);
     assert!( addr.matches(2, "foo", &EMPTY_STATE).0);
-    let state = MatchState { left_match: Some(2), right_match: None };
+    let state = MatchState { left_match: Some(2), right_match: None, depth: 0 };
     assert!( addr.matches(3, "match", &state).0);
     assert!( addr.matches(4, "bar", &state).0);
-    let state = MatchState { left_match: Some(2), right_match: Some(4) };
+    let state = MatchState { left_match: Some(2), right_match: Some(4), depth: 0 };
     assert!(!addr.matches(5, "un-match", &state).0);
 }
 
@@ -369,3 +370,2096 @@ fn matches_double_regex_range_with_multiple_matches_on_same_line() {
     let (is_match, _state) = addr.matches(5, "match", &state);
     assert!(is_match, "line 5 failed");
 }
+
+#[test]
+fn balanced_block_tracks_nesting() {
+    let example = [
+        "server",
+        "{",
+        "  location",
+        "  {",
+        "    root",
+        "  }",
+        "  listen",
+        "}",
+        "other",
+    ].join("\n");
+    let expected = vec![
+        "server",
+        "# {",
+        "#   location",
+        "#   {",
+        "#     root",
+        "#   }",
+        "#   listen",
+        "# }",
+        "other",
+    ];
+    let pattern = AddressPattern::new_single(RegexPattern(Regex::new("server").unwrap())).balanced('{', '}').unwrap();
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Comment));
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn balanced_block_starts_at_anchor_opener() {
+    let example = [
+        "{",
+        "  {",
+        "  }",
+        "}",
+        "{",
+        "}",
+    ].join("\n");
+    let expected = vec![
+        "# {",
+        "#   {",
+        "#   }",
+        "# }",
+        "{",
+        "}",
+    ];
+    let pattern = AddressPattern::new_single(Line(1)).balanced('{', '}').unwrap();
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Comment));
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn balanced_block_rearms_on_next_anchor() {
+    let re = Regex::new("block").unwrap();
+    let pattern = AddressPattern::new_single(RegexPattern(re)).balanced('(', ')').unwrap();
+    let lines = vec![
+        "block",
+        "(",
+        ")",
+        "between",
+        "block",
+        "(",
+        "(",
+        ")",
+        ")",
+    ];
+
+    let matches = get_matches(&pattern, &lines, EMPTY_STATE.unchanged());
+    assert_eq!(matches.len(), 4);
+    assert_eq!(matches[1], (true, vec!["(", ")"]));
+    assert_eq!(matches[3], (true, vec!["(", "(", ")", ")"]));
+}
+
+#[test]
+fn balanced_block_requires_single_address() {
+    let pattern = AddressPattern::new_range(Line(1), Line(2));
+    assert!(pattern.balanced('{', '}').is_err());
+    assert!(try_parse_delimiter("{{").is_err());
+    assert_eq!(try_parse_delimiter("{").unwrap(), '{');
+}
+
+#[test]
+fn surround_wraps_each_matched_block() {
+    let example = [
+        "a = 1",
+        "b = 2",
+        "c = 3",
+        "d = 4",
+    ].join("\n");
+    let expected = vec![
+        "a = 1",
+        "# ---",
+        "# b = 2",
+        "# c = 3",
+        "# ---",
+        "d = 4",
+    ];
+    let pattern = AddressPattern::new_range(Line(2), Line(3));
+    let surround = Surround { before: Some("# ---"), after: Some("# ---") };
+    let options = Options { surround, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn surround_is_not_toggled_with_block() {
+    let example = [
+        "# a = 1",
+        "b = 2",
+        "# c = 3",
+    ].join("\n");
+    let expected = vec![
+        "a = 1",
+        "// end",
+        "b = 2",
+        "c = 3",
+        "// end",
+    ];
+    let re = Regex::new("#").unwrap();
+    let pattern = AddressPattern::new_single(RegexPattern(re));
+    let surround = Surround { before: None, after: Some("// end") };
+    let options = Options { surround, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn unresolved_from_end_counts_back_from_the_last_line() {
+    let lines = vec!["a", "b", "c"];
+    let pattern = try_parse_pattern("-1").unwrap();
+    assert_eq!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()), vec![(false, vec!["a", "b"]), (true, vec!["c"])]);
+    let pattern = try_parse_pattern("-2,-1").unwrap();
+    assert_eq!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()), vec![(false, vec!["a"]), (true, vec!["b", "c"])]);
+    let pattern = AddressPattern::new_single(AddressComponent::Relative(2));
+    assert_eq!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()), vec![(false, vec!["a"]), (true, vec!["b"]), (false, vec!["c"])]);
+}
+
+#[test]
+fn from_end_resolves_against_line_count() {
+    let mut pattern = try_parse_pattern("-2").unwrap();
+    pattern.resolve_from_end(5);
+    assert_matches_lines!(pattern, 4);
+    assert_not_matches_lines!(pattern, 1, 2, 3, 5);
+
+    let mut pattern = try_parse_pattern("2,-1").unwrap();
+    pattern.resolve_from_end(5);
+    assert_matches_lines!(pattern, 2, 3, 4, 5);
+    assert_not_matches_lines!(pattern, 1);
+}
+
+#[test]
+fn from_end_rejects_zero_offset() {
+    assert!(try_parse_pattern("-0").is_err());
+    assert!(try_parse_pattern("-x").is_err());
+}
+
+#[test]
+fn validate_accepts_well_formed_patterns() {
+    assert!(validate_pattern("/nobody/,+3!").is_ok());
+    assert!(validate_pattern("").is_ok());
+}
+
+#[test]
+fn validate_rejects_malformed_patterns() {
+    assert!(matches!(validate_pattern("/a/,x"), Err(ToggleError::Parse(m)) if m == "unable to parse component"));
+    assert!(matches!(validate_pattern("/(/"), Err(ToggleError::Regex(_))));
+    // These once parsed, then panicked on the first line matched
+    assert!(validate_pattern("+3").is_err());
+    assert!(validate_pattern("+3!").is_err());
+    assert!(validate_pattern("~3").is_err());
+    assert!(validate_pattern("5,~2").is_err());
+    assert!(validate_pattern("/a/,~2").is_err());
+    assert!(validate_pattern("+2,-2").is_ok());
+    assert!(validate_pattern("/a/,+2").is_ok());
+}
+
+#[test]
+fn validate_rejects_huge_regex() {
+    assert!(matches!(validate_pattern("/a{1000}{1000}/"), Err(ToggleError::Regex(regex::Error::CompiledTooBig(_)))));
+    // Smaller repetitions stay under the validation limit
+    assert!(try_parse_pattern_with_limit("/a{100}{100}/", VALIDATE_REGEX_SIZE_LIMIT).is_ok());
+}
+
+#[test]
+fn stats_count_matched_and_changed_lines() {
+    let example = [
+        "a = 1",
+        "# b = 2",
+        "",
+        "d = 4",
+        "e = 5",
+    ].join("\n");
+    let pattern = AddressPattern::new_range(Line(1), Line(3));
+    let mut stats = Stats::default();
+    body_with_stats(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Comment), &mut stats);
+    assert_eq!(stats, Stats { lines: 5, matched: 3, changed: 1 });
+}
+
+#[test]
+fn skip_marker_leaves_marked_lines_in_range() {
+    let example = [
+        "a = 1",
+        "b = 2  # noqa: keep",
+        "c = 3",
+        "d = 4",
+    ].join("\n");
+    let expected = vec![
+        "# a = 1",
+        "b = 2  # noqa: keep",
+        "# c = 3",
+        "d = 4",
+    ];
+    let pattern = AddressPattern::new_range(Line(1), Line(3));
+    let options = Options { skip_marker: Some(Regex::new("keep$").unwrap()), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn skip_marker_does_not_decide_toggle_direction() {
+    let example = [
+        "# a = 1",
+        "b = 2  # keep",
+        "# c = 3",
+    ].join("\n");
+    let expected = vec![
+        "a = 1",
+        "b = 2  # keep",
+        "c = 3",
+    ];
+    let pattern = AddressPattern::new_zero();
+    let options = Options { skip_marker: Some(Regex::new("# keep").unwrap()), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn empty_first_address_starts_at_first_line() {
+    let pattern = try_parse_pattern(",5").unwrap();
+    assert_matches_lines!(pattern, 1, 2, 3, 4, 5);
+    assert_not_matches_lines!(pattern, 6);
+
+    let pattern = try_parse_pattern(",5!").unwrap();
+    assert_not_matches_lines!(pattern, 1, 5);
+    assert_matches_lines!(pattern, 6);
+}
+
+#[test]
+fn doubled_comma_is_an_error() {
+    let message = "empty address component around ','";
+    assert!(matches!(try_parse_pattern(",,"), Err(ToggleError::Parse(m)) if m == message));
+    assert!(matches!(try_parse_pattern("3,,5"), Err(ToggleError::Parse(m)) if m == message));
+    assert!(matches!(try_parse_pattern("3,5,"), Err(ToggleError::Parse(m)) if m == message));
+    assert!(try_parse_pattern("/a,,b/,5").is_ok());
+}
+
+#[test]
+fn third_address_is_an_error() {
+    let message = "too many addresses around ',', expected at most 2";
+    assert!(matches!(try_parse_pattern("1,2,3"), Err(ToggleError::Parse(m)) if m == message));
+    assert!(matches!(try_parse_pattern("/a/,/b/,/c/!"), Err(ToggleError::Parse(m)) if m == message));
+    assert!(matches!(try_parse_pattern_with("1;2;3", DEFAULT_REGEX_SIZE_LIMIT, ';'), Err(ToggleError::Parse(m)) if m == "too many addresses around ';', expected at most 2"));
+    assert!(try_parse_pattern("/a,b,c/,3").is_ok());
+}
+
+#[test]
+fn separator_errors_name_the_separator() {
+    let parse = |s| try_parse_pattern_with(s, DEFAULT_REGEX_SIZE_LIMIT, ';');
+    assert!(matches!(parse("3;;5"), Err(ToggleError::Parse(m)) if m == "empty address component around ';'"));
+    assert!(matches!(parse(";"), Err(ToggleError::Parse(m)) if m == "missing addresses around ';'"));
+}
+
+#[test]
+fn unterminated_or_empty_regex_is_an_error() {
+    for s in ["/", "/a/,/", "/a", "/a/,/b", r"/a\/"].iter() {
+        assert!(matches!(try_parse_pattern(s), Err(ToggleError::Parse(m)) if m == "unterminated /regex/"), "{}", s);
+    }
+    assert!(matches!(try_parse_pattern("//"), Err(ToggleError::Parse(m)) if m == "empty /regex/"));
+    assert!(matches!(try_parse_pattern("/a/,//"), Err(ToggleError::Parse(m)) if m == "empty /regex/"));
+    assert!(try_parse_pattern(r"/a\\/").is_ok());
+}
+
+#[test]
+fn try_parse_regex_strips_one_slash_at_each_end() {
+    assert_eq!(try_parse_regex(r"/a\//", DEFAULT_REGEX_SIZE_LIMIT).unwrap().as_str(), "a/");
+    assert_eq!(try_parse_regex("/^TODO//", DEFAULT_REGEX_SIZE_LIMIT).unwrap().as_str(), "^TODO/");
+    assert!(matches!(try_parse_regex("TODO", DEFAULT_REGEX_SIZE_LIMIT), Err(ToggleError::Parse(m)) if m == "expected a /regex/"));
+    assert!(matches!(try_parse_regex("/TODO", DEFAULT_REGEX_SIZE_LIMIT), Err(ToggleError::Parse(m)) if m == "unterminated /regex/"));
+    assert!(matches!(try_parse_regex("/a{1000}{1000}/", 1024), Err(ToggleError::Regex(_))));
+}
+
+#[test]
+fn lone_comma_is_an_error() {
+    assert!(try_parse_pattern(",").is_err());
+    assert!(try_parse_pattern(",!").is_err());
+    assert!(try_parse_pattern("5,").is_err());
+}
+
+#[test]
+fn number_comments_labels_across_blocks() {
+    let example = [
+        "a = 1",
+        "b = 2",
+        "c = 3",
+        "# d = 4",
+        "e = 5",
+    ].join("\n");
+    let expected = vec![
+        "# 1 a = 1",
+        "b = 2",
+        "# 2 c = 3",
+        "# d = 4",
+        "# 3 e = 5",
+    ];
+    let pattern = try_parse_pattern("2!").unwrap();
+    let options = Options { number_comments: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn repeated_marker_comments_banner() {
+    let prefix = repeat_marker("#", 3);
+    assert_eq!(prefix, "### ");
+    let example = [
+        "Section",
+        "  detail",
+    ].join("\n");
+    let expected = vec![
+        "### Section",
+        "###   detail",
+    ];
+    let pattern = AddressPattern::new_zero();
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new(&prefix, CommentingMode::Toggle));
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn repeated_marker_uncomments_banner() {
+    let prefix = repeat_marker("# ", 3);
+    let example = [
+        "### Section",
+        "  ### detail",
+        "# plain",
+    ].join("\n");
+    let expected = vec![
+        "Section",
+        "  detail",
+        "# plain",
+    ];
+    let pattern = AddressPattern::new_zero();
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new(&prefix, CommentingMode::Uncomment));
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn single_block_toggles_all_selected_chunks_together() {
+    let example = [
+        "# a = 1",
+        "b = 2",
+        "keep = 3",
+        "# d = 4",
+        "# e = 5",
+    ].join("\n");
+    // Only the first chunk has an uncommented line, but it decides for both
+    let expected = vec![
+        "# # a = 1",
+        "# b = 2",
+        "keep = 3",
+        "# # d = 4",
+        "# # e = 5",
+    ];
+    let pattern = AddressPattern::new_single(RegexPattern(Regex::new("keep").unwrap())).invert();
+    let options = Options { single_block: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    assert_eq!(body(actual.join("\n").lines(), EMPTY_STATE.unchanged(), &pattern, &options), example.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn double_regex_range_closes_on_line_matching_both() {
+    let re1 = Regex::new("nobody").unwrap();
+    let re2 = Regex::new("you").unwrap();
+    let pattern = AddressPattern::new_range(RegexPattern(re1), RegexPattern(re2));
+    let lines = vec![
+        "nobody and you",
+        "you and nobody",
+        "outside",
+        "nobody",
+        "inside",
+        "you",
+    ];
+
+    let matches = get_matches(&pattern, &lines, EMPTY_STATE.unchanged());
+    assert_eq!(matches, vec![
+        (true, vec!["nobody and you", "you and nobody"]),
+        (false, vec!["outside"]),
+        (true, vec!["nobody", "inside", "you"]),
+    ]);
+}
+
+#[test]
+fn toggle_block_is_comment_block_in_toggle_mode() {
+    let example = vec![
+        "# a = 1",
+        "",
+        "b = 2",
+    ];
+    assert_eq!(toggle_block(&PREFIX, "# ", &example), comment_block(&CommentingMode::Toggle, "# ", &example));
+    let commented = vec![
+        "# a = 1",
+        "",
+        "# b = 2",
+    ];
+    assert_eq!(toggle_block(&PREFIX, "# ", &commented), comment_block(&CommentingMode::Toggle, "# ", &commented));
+}
+
+#[test]
+fn toggle_error_variants_display() {
+    use std::error::Error;
+
+    let err = ToggleError::Parse("unable to parse component".into());
+    assert_eq!(err.to_string(), "unable to parse pattern: unable to parse component");
+    assert!(err.source().is_none());
+
+    let err = ToggleError::Io(io::Error::new(io::ErrorKind::NotFound, "missing.txt: not found"));
+    assert_eq!(err.to_string(), "missing.txt: not found");
+    assert!(err.source().is_some());
+
+    let err = ToggleError::from(regex::Error::Syntax("unclosed group".to_string()));
+    assert!(err.to_string().starts_with("invalid regex: "));
+    assert!(err.source().is_some());
+
+    let err = ToggleError::Usage("bad option".to_string());
+    assert_eq!(err.to_string(), "bad option");
+}
+
+#[test]
+fn toggle_error_boxes_into_dyn_error() {
+    fn parse(s: &str) -> Result<AddressPattern, Box<dyn std::error::Error>> {
+        Ok(try_parse_pattern(s)?)
+    }
+    assert!(parse("1,x").is_err());
+}
+
+#[test]
+fn requires_matches_content_after_uncommenting() {
+    let example = [
+        "# TODO: fix this",
+        "TODO: and this",
+        "done = 1",
+        "# done = 2",
+    ].join("\n");
+    let expected = vec![
+        "# # TODO: fix this",
+        "# TODO: and this",
+        "done = 1",
+        "# done = 2",
+    ];
+    let pattern = AddressPattern::new_zero();
+    let options = Options { requires: Some(Regex::new("^TODO").unwrap()), ..Options::new("# ", CommentingMode::Comment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# TODO: fix this", "# TODO: and this", "done = 1", "# done = 2"]);
+
+    let options = Options { requires: Some(Regex::new("^TODO").unwrap()), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn requires_uncomments_only_matching_lines() {
+    let example = [
+        "# TODO: fix this",
+        "# done = 2",
+    ].join("\n");
+    let expected = vec![
+        "TODO: fix this",
+        "# done = 2",
+    ];
+    let pattern = AddressPattern::new_zero();
+    let options = Options { requires: Some(Regex::new("TODO").unwrap()), ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn single_blank_line_is_left_alone_by_default() {
+    let example = [
+        "a = 1",
+        "",
+        "b = 2",
+    ].join("\n");
+    let pattern = AddressPattern::new_single(Line(2));
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Toggle));
+    assert_eq!(actual, vec!["a = 1", "", "b = 2"]);
+}
+
+#[test]
+fn comment_blank_comments_blank_lines_on_both_paths() {
+    let example = [
+        "a = 1",
+        "",
+        "b = 2",
+    ].join("\n");
+    let options = Options { comment_blank: true, ..Options::new("# ", CommentingMode::Toggle) };
+
+    let pattern = AddressPattern::new_single(Line(2));
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["a = 1", "# ", "b = 2"]);
+
+    let pattern = AddressPattern::new_range(Line(1), Line(3));
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# a = 1", "# ", "# b = 2"]);
+}
+
+#[test]
+fn hunk_header_selects_new_file_lines() {
+    let pattern = try_parse_hunk("@@ -10,5 +12,6 @@ fn context()").unwrap();
+    assert_matches_lines!(pattern, 12, 15, 17);
+    assert_not_matches_lines!(pattern, 10, 11, 18);
+
+    let pattern = try_parse_hunk("@@ -3 +4 @@").unwrap();
+    assert_matches_lines!(pattern, 4);
+    assert_not_matches_lines!(pattern, 3, 5);
+}
+
+#[test]
+fn hunk_header_without_additions_selects_nothing() {
+    let pattern = try_parse_hunk("@@ -10,2 +9,0 @@").unwrap();
+    assert_not_matches_lines!(pattern, 8, 9, 10);
+}
+
+#[test]
+fn malformed_hunk_header_is_an_error() {
+    assert!(try_parse_hunk("@@ 10,5 12,6 @@").is_err());
+    assert!(try_parse_hunk("-10,5 +12,6").is_err());
+}
+
+#[test]
+fn box_aligns_closing_markers() {
+    let example = [
+        "before",
+        "Title",
+        "",
+        "A longer subtitle",
+        "after",
+    ].join("\n");
+    let expected = vec![
+        "before",
+        "# Title             #",
+        "",
+        "# A longer subtitle #",
+        "after",
+    ];
+    let pattern = AddressPattern::new_range(Line(2), Line(4));
+    let options = Options { boxed: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn box_width_counts_characters_not_bytes() {
+    let actual = box_block("// ", &["héllo", "hi"], None);
+    assert_eq!(actual, vec!["// héllo //", "// hi    //"]);
+}
+
+#[test]
+fn box_round_trips() {
+    let example = ["before", "Title", "", "A longer subtitle", "after"].join("\n");
+    let pattern = try_parse_pattern("2,4").unwrap();
+    for &mode in ["comment", "toggle"].iter() {
+        let options = Options { boxed: true, ..Options::new("# ", mode.parse().unwrap()) };
+        let boxed = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options).join("\n");
+        assert_eq!(boxed, ["before", "# Title             #", "", "# A longer subtitle #", "after"].join("\n"));
+        // Commenting again leaves the box alone, toggling or uncommenting takes it apart
+        let again = body(boxed.lines(), EMPTY_STATE.unchanged(), &pattern, &Options { boxed: true, ..Options::new("# ", CommentingMode::Comment) });
+        assert_eq!(again.join("\n"), boxed);
+        for &undo in ["toggle", "uncomment"].iter() {
+            let restored = body(boxed.lines(), EMPTY_STATE.unchanged(), &pattern, &Options { boxed: true, ..Options::new("# ", undo.parse().unwrap()) });
+            assert_eq!(restored.join("\n"), example, "{} then {}", mode, undo);
+            let plain = body(boxed.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", undo.parse().unwrap()));
+            assert_eq!(plain.join("\n"), example, "{} then plain {}", mode, undo);
+        }
+    }
+}
+
+#[test]
+fn unbox_needs_a_whole_box() {
+    assert_eq!(unbox_block("# ", &["# a  #", "# bc #"]), Some(vec!["a".to_string(), "bc".to_string()]));
+    assert_eq!(unbox_block("# ", &["# a #", "# bc #"]), None);
+    assert_eq!(unbox_block("# ", &["# a #", "b #"]), None);
+    assert_eq!(unbox_block("# ", &["", " "]), None);
+}
+
+#[test]
+fn detect_prefix_picks_most_common_marker() {
+    let example = [
+        "// header",
+        "#include <stdio.h>",
+        "int main() {",
+        "    // say hello",
+        "    # not really a comment",
+        "    puts(\"hi\"); // trailing",
+        "}",
+    ].join("\n");
+    assert_eq!(detect_prefix(&example), Some("// ".to_string()));
+}
+
+#[test]
+fn detect_prefix_requires_whitespace_after_marker() {
+    assert_eq!(detect_prefix("#!/bin/sh\n--\necho hi\n"), None);
+    assert_eq!(detect_prefix("; one\n% two\n"), Some("; ".to_string()));
+}
+
+#[test]
+fn force_comments_already_commented_lines() {
+    let example = [
+        "# a = 1",
+        "b = 2",
+    ].join("\n");
+    let pattern = AddressPattern::new_single(Line(1));
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Comment));
+    assert_eq!(actual, vec!["# a = 1", "b = 2"]);
+
+    let options = Options { force: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# # a = 1", "b = 2"]);
+
+    let pattern = AddressPattern::new_zero();
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# # a = 1", "# b = 2"]);
+}
+
+#[test]
+fn force_only_affects_comment_mode() {
+    let example = "# a = 1";
+    let pattern = AddressPattern::new_single(Line(1));
+    let options = Options { force: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["a = 1"]);
+}
+
+#[test]
+fn unicode_whitespace_is_indentation_by_default() {
+    let example = "\u{a0}\u{a0}# a = 1\n\u{a0}";
+    let pattern = AddressPattern::new_zero();
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Toggle));
+    assert_eq!(actual, vec!["\u{a0}\u{a0}a = 1", "\u{a0}"]);
+}
+
+#[test]
+fn ascii_whitespace_excludes_non_breaking_space() {
+    let example = "\u{a0}\u{a0}# a = 1\n\u{a0}";
+    let pattern = AddressPattern::new_zero();
+    let options = Options { ascii: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# \u{a0}\u{a0}# a = 1", "# \u{a0}"]);
+}
+
+#[test]
+fn duplicates_keeps_first_occurrence() {
+    let example = [
+        "import os",
+        "import sys",
+        "import os",
+        "x = 1",
+        "import sys",
+        "import sys",
+    ].join("\n");
+    let expected = vec![
+        "import os",
+        "import sys",
+        "# import os",
+        "x = 1",
+        "# import sys",
+        "# import sys",
+    ];
+    let pattern = AddressPattern::new_zero();
+    let options = Options { duplicates: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn duplicates_combine_with_address() {
+    let example = [
+        "a",
+        "a",
+        "b",
+        "a",
+    ].join("\n");
+    let pattern = AddressPattern::new_range(Line(1), Line(3));
+    let options = Options { duplicates: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["a", "# a", "b", "a"]);
+}
+
+#[test]
+fn reindent_recovers_code_at_column() {
+    let example = [
+        "def main():",
+        "# if ready:",
+        "#     go()",
+        "",
+        "# done()",
+    ].join("\n");
+    let expected = vec![
+        "def main():",
+        "    if ready:",
+        "    go()",
+        "",
+        "    done()",
+    ];
+    let pattern = AddressPattern::new_range(Line(2), Line(5));
+    let options = Options { reindent: Some(4), ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn reindent_replaces_recovered_indentation() {
+    let mut lines = vec!["\t\tx = 1".to_string(), "\t\t    y = 2".to_string(), "  ".to_string()];
+    reindent_block(&mut lines, 4);
+    assert_eq!(lines, vec!["    x = 1", "    y = 2", "  "]);
+}
+
+#[test]
+fn parsed_patterns_equal_hand_built_patterns() {
+    use AddressComponent::*;
+    assert_eq!(try_parse_pattern("3").unwrap(), address_range!(Address::OneAddress(Line(3))));
+    assert_eq!(try_parse_pattern("2,+4!").unwrap(), address_range!(Address::AddressRange(Line(2), Relative(4)), true));
+    assert_eq!(
+        try_parse_pattern("/start/,/end/").unwrap(),
+        address_range!(Address::AddressRange(RegexPattern(Regex::new("start").unwrap()), RegexPattern(Regex::new("end").unwrap()))),
+    );
+    assert_ne!(try_parse_pattern("/start/").unwrap(), try_parse_pattern("/stop/").unwrap());
+    assert_ne!(try_parse_pattern("3").unwrap(), try_parse_pattern("-3").unwrap());
+}
+
+#[test]
+fn cloned_pattern_equals_original() {
+    let pattern = try_parse_pattern("/a/,5").unwrap();
+    let inverted = pattern.clone().invert();
+    assert_eq!(pattern, try_parse_pattern("/a/,5").unwrap());
+    assert_ne!(pattern, inverted);
+}
+
+#[test]
+fn line_stream_agrees_with_body() {
+    let example = [
+        "INFO: start",
+        "ERROR: one",
+        "# ERROR: two",
+        "",
+        "INFO: done",
+    ].join("\n");
+    for &mode in ["comment", "toggle", "uncomment"].iter() {
+        let pattern = try_parse_pattern("/ERROR/").unwrap();
+        let options = Options::new("# ", mode.parse().unwrap());
+        let expected = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+        let mut stats = Stats::default();
+        let mut stream = LineStream::new(&pattern, &options);
+        let actual: Vec<String> = example.lines().map(|l| stream.process(l, &mut stats)).collect();
+        assert_eq!(actual, expected);
+        assert_eq!(stats.lines, 5);
+        assert_eq!(stats.matched, 2);
+    }
+}
+
+#[test]
+fn line_stream_writes_what_it_processes() {
+    let example = ["ERROR: one", "# ERROR: two", "INFO: three"];
+    let pattern = try_parse_pattern("/ERROR/").unwrap();
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let mut written = vec![];
+    let mut stream = LineStream::new(&pattern, &options);
+    for line in example.iter() {
+        stream.write_line(&mut written, line, &mut Stats::default()).unwrap();
+    }
+    assert_eq!(String::from_utf8(written).unwrap(), "# ERROR: one\nERROR: two\nINFO: three\n");
+}
+
+#[test]
+fn commenting_borrows_the_line() {
+    match comment_line(&PREFIX, "# ", "x = 1") {
+        Rewritten::Prefixed(prefix, line) => assert_eq!((prefix, line), ("# ", "x = 1")),
+        other => panic!("expected the prefix and line as pieces, got {:?}", other),
+    }
+    assert!(matches!(comment_line(&PREFIX, "# ", "# x = 1"), Rewritten::Line(Cow::Borrowed("# x = 1"))));
+}
+
+#[test]
+fn streamable_rejects_lookahead() {
+    let options = Options::new("# ", CommentingMode::Comment);
+    assert!(options.is_streamable(&try_parse_pattern("/a/,/b/").unwrap()));
+    assert!(!options.is_streamable(&try_parse_pattern("2,-1").unwrap()));
+
+    let options = Options::new("# ", CommentingMode::Toggle);
+    assert!(options.is_streamable(&try_parse_pattern("/a/").unwrap()));
+    assert!(!options.is_streamable(&try_parse_pattern("/a/,/b/").unwrap()));
+
+    let options = Options { boxed: true, ..Options::new("# ", CommentingMode::Comment) };
+    assert!(!options.is_streamable(&try_parse_pattern("/a/").unwrap()));
+}
+
+#[test]
+fn first_per_block_changes_only_block_heads() {
+    let example = [
+        "start a",
+        "  body",
+        "end",
+        "between",
+        "start b",
+        "  body",
+        "end",
+    ].join("\n");
+    let expected = vec![
+        "# start a",
+        "  body",
+        "end",
+        "between",
+        "# start b",
+        "  body",
+        "end",
+    ];
+    let pattern = try_parse_pattern("/start/,/end/").unwrap();
+    let options = Options { first_per_block: true, ..Options::new("# ", CommentingMode::Comment) };
+    let mut stats = Stats::default();
+    let actual = body_with_stats(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options, &mut stats);
+    assert_eq!(actual, expected);
+    assert_eq!(stats, Stats { lines: 7, matched: 6, changed: 2 });
+}
+
+#[test]
+fn bang_inside_regex_is_literal() {
+    let literal = try_parse_pattern("/foo!/").unwrap();
+    assert_eq!(literal, address_range!(Address::OneAddress(AddressComponent::RegexPattern(Regex::new("foo!").unwrap()))));
+    assert!(literal.matches(1, "foo!", &EMPTY_STATE).0);
+    assert!(!literal.matches(1, "foo", &EMPTY_STATE).0);
+
+    let negated = try_parse_pattern("/foo/!").unwrap();
+    assert_eq!(negated, address_range!(Address::OneAddress(AddressComponent::RegexPattern(Regex::new("foo").unwrap())), true));
+    assert!(!negated.matches(1, "foo!", &EMPTY_STATE).0);
+    assert!(negated.matches(1, "bar", &EMPTY_STATE).0);
+
+    assert!(try_parse_pattern("5!!").is_err());
+}
+
+#[test]
+fn trailing_whitespace_kept_by_default() {
+    let example = "x = 1  \ny = 2\t\nz = 3  ";
+    let pattern = try_parse_pattern("1,2").unwrap();
+    let options = Options::new("# ", CommentingMode::Comment);
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# x = 1  ", "# y = 2\t", "z = 3  "]);
+}
+
+#[test]
+fn trailing_whitespace_stripped_from_changed_lines_only() {
+    let example = "x = 1  \n# y = 2\t\nz = 3  ";
+    let pattern = try_parse_pattern("1,2").unwrap();
+    let options = Options { strip_trailing: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# x = 1", "# y = 2\t", "z = 3  "]);
+}
+
+#[test]
+fn inverted_line_range_matches_nothing() {
+    let pattern = try_parse_pattern("7,3").unwrap();
+    assert!(pattern.is_inverted());
+    assert_not_matches_lines!(pattern, 1, 3, 5, 7, 9);
+    assert!(!try_parse_pattern("3,7").unwrap().is_inverted());
+    assert!(!try_parse_pattern("3,3").unwrap().is_inverted());
+
+    let mut pattern = try_parse_pattern("5,-4").unwrap();
+    pattern.resolve_from_end(6);
+    assert!(pattern.is_inverted());
+    assert_not_matches_lines!(pattern, 1, 3, 5, 6);
+}
+
+#[test]
+fn relative_range_at_usize_max_does_not_overflow() {
+    let pattern = address_range!(Address::AddressRange(AddressComponent::Line(usize::MAX - 1), AddressComponent::Relative(5)));
+    assert_matches_lines!(pattern, usize::MAX - 1, usize::MAX);
+    let pattern = address_range!(Address::AddressRange(AddressComponent::Line(2), AddressComponent::Line(usize::MAX)));
+    assert_matches_lines!(pattern, 2, usize::MAX);
+}
+
+#[test]
+fn gutter_right_aligns_line_numbers() {
+    let lines: Vec<String> = (1..=10).map(|n| format!("line {}", n)).collect();
+    let actual = add_gutter(lines);
+    assert_eq!(actual[0], " 1 | line 1");
+    assert_eq!(actual[8], " 9 | line 9");
+    assert_eq!(actual[9], "10 | line 10");
+    assert_eq!(add_gutter(vec!["".to_string()]), vec!["1 | "]);
+    assert!(add_gutter(vec![]).is_empty());
+}
+
+#[test]
+fn baseline_selects_changed_lines() {
+    let baseline = "a = 1\nb = 2\nc = 3";
+    let example = "a = 1\nb = 20\nc = 3\nd = 4\ne = 5";
+    let options = Options { baseline: Some(baseline.to_string()), ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["a = 1", "# b = 20", "c = 3", "# d = 4", "# e = 5"]);
+}
+
+#[test]
+fn baseline_longer_than_input() {
+    let lines = ["a", "x"];
+    assert_eq!(changed_lines("a\nb\nc\nd", &lines), vec![false, true]);
+    assert_eq!(changed_lines("", &lines), vec![true, true]);
+}
+
+#[test]
+fn parse_prefix_mapping() {
+    assert_eq!(try_parse_prefix_mapping("rs=//").unwrap(), ("rs", "// ".to_string()));
+    assert_eq!(try_parse_prefix_mapping(".py=#").unwrap(), ("py", "# ".to_string()));
+    assert_eq!(try_parse_prefix_mapping("sql=-- ").unwrap(), ("sql", "-- ".to_string()));
+    assert!(try_parse_prefix_mapping("rs").is_err());
+    assert!(try_parse_prefix_mapping("=//").is_err());
+    assert!(try_parse_prefix_mapping("rs=").is_err());
+}
+
+#[test]
+fn indent_block_selects_python_function_body() {
+    let example = [
+        "class Greeter:",
+        "    def foo(self):",
+        "        x = 1",
+        "",
+        "        if x:",
+        "            return x",
+        "    def bar(self):",
+        "        pass",
+        "done = True",
+    ].join("\n");
+    let expected = vec![
+        "class Greeter:",
+        "#     def foo(self):",
+        "#         x = 1",
+        "",
+        "#         if x:",
+        "#             return x",
+        "    def bar(self):",
+        "        pass",
+        "done = True",
+    ];
+    let pattern = try_parse_pattern("/def foo/").unwrap().indent_block().unwrap();
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Toggle));
+    assert_eq!(actual, expected);
+
+    let pattern = try_parse_pattern("/class/").unwrap().indent_block().unwrap();
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Comment));
+    assert_eq!(actual.iter().filter(|l| l.contains("# ")).count(), 7);
+    assert_eq!(actual[8], "done = True");
+}
+
+#[test]
+fn indent_block_requires_single_address() {
+    assert!(try_parse_pattern("1,3").unwrap().indent_block().is_err());
+    assert!(try_parse_pattern("").unwrap().indent_block().is_err());
+}
+
+#[test]
+fn protected_doc_comments_survive_toggling() {
+    let example = [
+        "/// Adds one",
+        "fn inc(x: u32) -> u32 {",
+        "    //! inner docs",
+        "    x + 1",
+        "}",
+    ].join("\n");
+    let expected = vec![
+        "/// Adds one",
+        "// fn inc(x: u32) -> u32 {",
+        "    //! inner docs",
+        "//     x + 1",
+        "// }",
+    ];
+    let protect = vec![Regex::new(r"^\s*///").unwrap(), Regex::new(r"^\s*//!").unwrap()];
+    let options = Options { protect, ..Options::new("// ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, expected);
+
+    let options = Options { protect: vec![Regex::new(r"^\s*///").unwrap()], ..Options::new("// ", CommentingMode::Uncomment) };
+    let actual = body(expected.join("\n").lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, example.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn binary_detection_sniffs_for_nul() {
+    assert!(is_binary(b"\x7fELF\0\0"));
+    assert!(!is_binary("plain text, ünïcode\n".as_bytes()));
+    let mut late_nul = vec![b'a'; 10_000];
+    late_nul.push(0);
+    assert!(!is_binary(&late_nul));
+}
+
+#[test]
+fn range_with_unmatched_end_runs_to_eof() {
+    let lines = ["a", "start", "b", "c", "d"];
+    let expected = vec![(false, vec!["a"]), (true, vec!["start", "b", "c", "d"])];
+    let pattern = try_parse_pattern("/start/,/never/").unwrap();
+    assert_eq!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()), expected);
+    let pattern = try_parse_pattern("2,/never/").unwrap();
+    assert_eq!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()), expected);
+}
+
+#[test]
+fn joined_regex_matches_across_line_break() {
+    let lines = ["fn long_name(", "    x: u32) {", "    x", "}"];
+    let pattern = try_parse_pattern(r"/long_name\(\n\s*x/").unwrap();
+    assert!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()).iter().all(|(is_match, _)| !is_match));
+
+    let pattern = try_parse_pattern(r"/long_name\(\n\s*x/").unwrap().joined(2).unwrap();
+    assert_eq!(get_matches(&pattern, &lines, EMPTY_STATE.unchanged()), vec![(true, vec!["fn long_name(", "    x: u32) {"]), (false, vec!["    x", "}"])]);
+
+    let actual = body(lines.join("\n").lines(), EMPTY_STATE.unchanged(), &pattern.invert(), &Options::new("# ", CommentingMode::Comment));
+    assert_eq!(actual, vec!["fn long_name(", "    x: u32) {", "#     x", "# }"]);
+}
+
+#[test]
+fn joined_regex_selects_only_covered_lines() {
+    let lines = ["a", "b", "c"];
+    let pattern = try_parse_pattern("/b/").unwrap().joined(2).unwrap();
+    assert_eq!(line_matches(&pattern, &lines, EMPTY_STATE.unchanged()).0, vec![false, true, false]);
+    let pattern = try_parse_pattern(r"/a\n/").unwrap().joined(2).unwrap();
+    assert_eq!(line_matches(&pattern, &lines, EMPTY_STATE.unchanged()).0, vec![true, false, false]);
+    assert!(try_parse_pattern("1,2").unwrap().joined(2).is_err());
+    assert!(try_parse_pattern("/a/").unwrap().joined(0).is_err());
+}
+
+#[test]
+fn shebang_marker_from_interpreter() {
+    assert_eq!(shebang_marker("#!/usr/bin/env python3\nprint(1)"), Some("#"));
+    assert_eq!(shebang_marker("#!/usr/bin/python3.8 -u\n"), Some("#"));
+    assert_eq!(shebang_marker("#!/usr/bin/env -S node --harmony\n"), Some("//"));
+    assert_eq!(shebang_marker("#!/bin/sh\n"), Some("#"));
+    assert_eq!(shebang_marker("#!/usr/bin/env unknown\n"), None);
+    assert_eq!(shebang_marker("print(1)\n#!/bin/sh"), None);
+    assert_eq!(shebang_marker(""), None);
+}
+
+#[test]
+fn head_and_tail_relative_range() {
+    // On 6 lines, +2 is line 2 and -2 is the second from last, line 5, both inclusive
+    let mut pattern = try_parse_pattern("+2,-2").unwrap();
+    pattern.resolve_from_end(6);
+    assert_eq!(pattern, address_range!(Address::AddressRange(AddressComponent::Line(2), AddressComponent::Line(5))));
+    assert_not_matches_lines!(pattern, 1, 6);
+    assert_matches_lines!(pattern, 2, 3, 4, 5);
+
+    let example = "1\n2\n3\n4\n5\n6";
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Comment));
+    assert_eq!(actual, vec!["1", "# 2", "# 3", "# 4", "# 5", "6"]);
+}
+
+#[test]
+fn line_edits_describe_marker_changes() {
+    let before = ["x = 1", "    # y = 2", "z = 3", "naïve = 1"];
+    let after = ["# x = 1", "    y = 2", "z = 3", "# naïve = 1"];
+    assert_eq!(line_edits(&before, &after), vec![
+        TextEdit { line: 0, start: 0, end: 0, new_text: "# ".to_string() },
+        TextEdit { line: 1, start: 4, end: 6, new_text: "".to_string() },
+        TextEdit { line: 3, start: 0, end: 0, new_text: "# ".to_string() },
+    ]);
+
+    // Characters are UTF-16 code units, so 𝄞 counts as two
+    let edits = line_edits(&["𝄞 # x"], &["𝄞 x"]);
+    assert_eq!(edits, vec![TextEdit { line: 0, start: 3, end: 5, new_text: "".to_string() }]);
+}
+
+#[test]
+fn edits_serialize_to_lsp_json() {
+    let edits = vec![TextEdit { line: 2, start: 4, end: 4, new_text: "// \"q\"".to_string() }];
+    assert_eq!(
+        edits_to_json(&edits),
+        r#"[{"range":{"start":{"line":2,"character":4},"end":{"line":2,"character":4}},"newText":"// \"q\""}]"#,
+    );
+    assert_eq!(edits_to_json(&[]), "[]");
+}
+
+#[test]
+fn parse_script_steps() {
+    let steps = try_parse_script("3,7 comment; /TODO list/ uncomment;").unwrap();
+    let steps: Vec<(&str, String)> = steps.into_iter().map(|(pattern, mode)| (pattern, mode.to_string())).collect();
+    assert_eq!(steps, vec![("3,7", "Comment".to_string()), ("/TODO list/", "Uncomment".to_string())]);
+    assert!(try_parse_script("comment").is_err());
+    assert!(try_parse_script("3,7 delete").is_err());
+    for script in ["", " ; ", ";;"].iter() {
+        assert!(matches!(try_parse_script(script), Err(ToggleError::Parse(m)) if m == "empty script"), "{:?}", script);
+    }
+    // A `;` inside a regex belongs to it, even after an escaped slash
+    let steps = try_parse_script(r"/a;b/ comment; /c\/;d/ toggle").unwrap();
+    let patterns: Vec<&str> = steps.iter().map(|(pattern, _)| *pattern).collect();
+    assert_eq!(patterns, vec!["/a;b/", r"/c\/;d/"]);
+}
+
+#[test]
+fn line_shapes_in_ini_file() {
+    let example = [
+        "[server]",
+        "host = example.com",
+        "port: 8080",
+        "; a comment",
+        "",
+        "stray text here",
+    ].join("\n");
+    let shapes: Vec<Vec<bool>> = example.lines().map(|line| {
+        [LineShape::Assignment, LineShape::Section, LineShape::Comment, LineShape::Blank].iter().map(|s| s.matches(line, "# ")).collect()
+    }).collect();
+    assert_eq!(shapes, vec![
+        vec![false, true, false, false],
+        vec![true, false, false, false],
+        vec![true, false, false, false],
+        vec![false, false, true, false],
+        vec![false, false, false, true],
+        vec![false, false, false, false],
+    ]);
+
+    let options = Options { shape: Some((LineShape::Assignment, true)), comment_blank: true, ..Options::new("; ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["; [server]", "host = example.com", "port: 8080", "; a comment", "; ", "; stray text here"]);
+}
+
+#[test]
+fn expand_tabs_on_commented_lines() {
+    let example = "\tx = 1\n\ty = 2";
+    let pattern = try_parse_pattern("1").unwrap();
+    let options = Options { retab: Some(Retab::Expand(4)), ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["#     x = 1", "\ty = 2"]);
+}
+
+#[test]
+fn retab_line_counts_columns() {
+    assert_eq!(retab_line("  \tx", "# ", Retab::Expand(4)), "    x");
+    assert_eq!(retab_line("# \t\tx", "# ", Retab::Expand(2)), "#     x");
+    assert_eq!(retab_line("          x", "# ", Retab::Unexpand(4)), "\t\t  x");
+    assert_eq!(retab_line("#   \tx", "# ", Retab::Unexpand(4)), "# \tx");
+    assert_eq!(retab_line("x", "# ", Retab::Expand(4)), "x");
+}
+
+#[test]
+fn json_round_trips_strings() {
+    let text = "tab\there \"quoted\" back\\slash\u{1} ünï 𝄞";
+    assert_eq!(json::parse(&json::quote(text)).unwrap(), json::Json::String(text.to_string()));
+    assert_eq!(json::parse(r#""𝄞""#).unwrap(), json::Json::String("𝄞".to_string()));
+    let value = json::parse(r#" {"a": [1, 2.5, true, null], "b": {}} "#).unwrap();
+    assert_eq!(value.get("a").and_then(json::Json::as_array).map(<[_]>::len), Some(4));
+    assert!(json::parse("[1, 2").is_err());
+    assert!(json::parse("{} x").is_err());
+}
+
+#[test]
+fn undo_record_restores_original() {
+    let original = "x = 1\ny = 2\nz = 3\n";
+    let written = "# x = 1\ny = 2\n# z = 3\n";
+    let record = undo::UndoRecord::new("f.py", original, written).unwrap();
+    assert_eq!(record.changes, vec![(1, "x = 1".to_string()), (3, "z = 3".to_string())]);
+
+    let log = undo::log_to_json(&[record]);
+    let records = undo::log_from_json(&log).unwrap();
+    assert_eq!(records[0].restore(written).unwrap(), original);
+    assert!(records[0].restore("# x = 1\ny = 20\n# z = 3\n").is_err());
+    assert!(undo::UndoRecord::new("f.py", original, "extra\n").is_err());
+}
+
+#[test]
+fn single_regex_matches_every_line_regardless_of_state() {
+    let lines = ["foo 1", "bar", "foo 2", "foo 3", "baz foo"];
+    let pattern = try_parse_pattern("/foo/").unwrap();
+    let expected = vec![true, false, true, true, true];
+    assert_eq!(line_matches(&pattern, &lines, EMPTY_STATE.unchanged()).0, expected);
+
+    // State left over from a range or block must not leak into a single address
+    let stale = MatchState { left_match: Some(1), right_match: None, depth: 3 };
+    assert_eq!(line_matches(&pattern, &lines, stale).0, expected);
+    for (idx, line) in lines.iter().enumerate() {
+        let (is_match, new_state) = pattern.matches(idx + 1, line, &EMPTY_STATE);
+        assert_eq!(is_match, expected[idx]);
+        assert_eq!((new_state.left_match, new_state.right_match, new_state.depth), (None, None, 0));
+    }
+
+    let example = lines.join("\n");
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Toggle));
+    assert_eq!(actual, vec!["# foo 1", "bar", "# foo 2", "# foo 3", "# baz foo"]);
+}
+
+#[test]
+fn space_boundary_distinguishes_lua_block_comments() {
+    let example = "-- x\n--[[ x\n--- doc\n--";
+    let options = Options { space_boundary: true, ..Options::new("--", CommentingMode::Uncomment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec![" x", "--[[ x", "--- doc", ""]);
+
+    let options = Options::new("--", CommentingMode::Uncomment);
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec![" x", "[[ x", "- doc", ""]);
+
+    let prefix_pattern = prefix_regex("--", false, true);
+    assert!(prefix_pattern.is_match("  -- x"));
+    assert!(!prefix_pattern.is_match("  --[[ x"));
+}
+
+#[test]
+fn squeeze_collapses_blank_runs() {
+    let lines: Vec<String> = ["a", "", "  ", "", "b", "", "c", "\t", ""].iter().map(|s| s.to_string()).collect();
+    assert_eq!(squeeze_blank_runs(lines), vec!["a", "", "b", "", "c", "\t"]);
+    assert!(squeeze_blank_runs(vec![]).is_empty());
+}
+
+#[test]
+fn and_regexes_require_every_pattern_on_the_line() {
+    let example = [
+        r#"<div class="ad">"#,
+        r#"<div class="content">"#,
+        r#"<span class="ad">"#,
+        r#"<div id="x" class="ad banner">"#,
+    ].join("\n");
+    let pattern = try_parse_pattern("/<div/").unwrap();
+    let options = Options { and_regexes: vec![Regex::new(r#"class="ad\b"#).unwrap()], ..Options::new("<!-- ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec![
+        r#"<!-- <div class="ad">"#,
+        r#"<div class="content">"#,
+        r#"<span class="ad">"#,
+        r#"<!-- <div id="x" class="ad banner">"#,
+    ]);
+}
+
+#[test]
+fn tag_line_number_round_trips() {
+    let example = [
+        "def f():",
+        "    x = 1",
+        "    return x",
+    ].join("\n");
+    let pattern = try_parse_pattern("2,3").unwrap();
+    let options = Options { tag_line_number: true, ..Options::new("# ", CommentingMode::Comment) };
+    let commented: Vec<String> = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(commented, vec![
+        "def f():",
+        "# [L2]     x = 1",
+        "# [L3]     return x",
+    ]);
+    let commented = commented.join("\n");
+    let options = Options { tag_line_number: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let uncommented: Vec<String> = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(uncommented.join("\n"), example);
+}
+
+#[test]
+fn tag_line_number_only_strips_tags() {
+    assert_eq!(tag_line_number("  x", "  # x", "# ", 7), "  # [L7] x");
+    assert_eq!(strip_line_tag("  [L12] x"), "  x");
+    assert_eq!(strip_line_tag("[L] x"), "[L] x");
+    assert_eq!(strip_line_tag("[Lx] y"), "[Lx] y");
+    assert_eq!(strip_line_tag("[L3]x"), "[L3]x");
+}
+
+#[test]
+fn regex_addresses_may_contain_the_separator() {
+    let expected = AddressPattern::new_range(
+        RegexPattern(Regex::new("a,b").unwrap()),
+        RegexPattern(Regex::new("c").unwrap()),
+    );
+    assert_eq!(try_parse_pattern("/a,b/,/c/").unwrap(), expected);
+    // An escaped slash doesn't end the regex early
+    let expected = AddressPattern::new_range(
+        RegexPattern(Regex::new("a/,b").unwrap()),
+        Line(4),
+    );
+    assert_eq!(try_parse_pattern(r"/a\/,b/,4").unwrap(), expected);
+    // Only one closing delimiter is taken off, leaving an escaped slash before it
+    let expected = AddressPattern::new_single(RegexPattern(Regex::new("a/").unwrap()));
+    assert_eq!(try_parse_pattern(r"/a\//").unwrap(), expected);
+    let expected = AddressPattern::new_range(Line(2), RegexPattern(Regex::new("x,y").unwrap()));
+    assert_eq!(try_parse_pattern_with("2;/x,y/", DEFAULT_REGEX_SIZE_LIMIT, ';').unwrap(), expected);
+}
+
+#[test]
+fn truncate_preview_at_width_boundary() {
+    assert_eq!(truncate_preview("abcde", 5), "abcde");
+    assert_eq!(truncate_preview("abcdef", 5), "abcd…");
+    assert_eq!(truncate_preview("# héllo", 4), "# h…");
+    assert_eq!(truncate_preview("", 0), "");
+}
+
+#[test]
+fn line_numbers_select_exactly_those_lines() {
+    assert!(matches!(try_parse_line_numbers("1\n0\n"), Err(ToggleError::Parse(_))));
+    assert!(matches!(try_parse_line_numbers("2\nx\n"), Err(ToggleError::Parse(_))));
+    let example = ["a", "b", "c", "d"].join("\n");
+    let options = Options { line_numbers: Some(try_parse_line_numbers(" 3\n\n1\n9\n").unwrap()), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["# a", "b", "# c", "d"]);
+}
+
+#[test]
+fn marker_styles_round_trip() {
+    let example = ["fn f() {", "    x();", "}"].join("\n");
+    for name in MarkerStyle::NAMES.iter() {
+        let style: MarkerStyle = name.parse().unwrap();
+        let options = Options { block_delimiters: style.delimiters(), ..Options::new(style.prefix(), CommentingMode::Toggle) };
+        let commented = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("2").unwrap(), &options);
+        let expected_len = if style.delimiters().is_some() { 5 } else { 3 };
+        assert_eq!(commented.len(), expected_len, "{}", name);
+        assert_ne!(commented.join("\n"), example, "{}", name);
+        let commented = commented.join("\n");
+        let range = try_parse_pattern(&format!("2,{}", expected_len - 1)).unwrap();
+        let uncommented = body(commented.lines(), EMPTY_STATE.unchanged(), &range, &options);
+        assert_eq!(uncommented.join("\n"), example, "{}", name);
+    }
+    assert!("hashes".parse::<MarkerStyle>().is_err());
+}
+
+#[test]
+fn block_c_keeps_delimiters_around_commented_block() {
+    let example = ["a", "/*", " * b", " */"].join("\n");
+    let options = Options { block_delimiters: MarkerStyle::BlockC.delimiters(), ..Options::new(" * ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("2,4").unwrap(), &options);
+    assert_eq!(actual, vec!["a", "/*", " * b", " */"]);
+}
+
+#[test]
+fn block_c_does_not_wrap_block_being_uncommented() {
+    let example = ["a", " * b", " * c"].join("\n");
+    let options = Options { block_delimiters: MarkerStyle::BlockC.delimiters(), ..Options::new(" * ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("2,3").unwrap(), &options);
+    assert_eq!(actual, vec!["a", "b", "c"]);
+}
+
+#[test]
+fn block_c_drops_delimiters_around_uncommented_inner_lines() {
+    let example = ["/*", " * a", " * b", " */"].join("\n");
+    let options = Options { block_delimiters: MarkerStyle::BlockC.delimiters(), ..Options::new(" * ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("2,3").unwrap(), &options);
+    assert_eq!(actual, vec!["a", "b"]);
+}
+
+#[test]
+fn block_c_splits_block_around_uncommented_lines() {
+    let example = ["/*", " * a", " * b", " * c", " */"].join("\n");
+    let options = Options { block_delimiters: MarkerStyle::BlockC.delimiters(), ..Options::new(" * ", CommentingMode::Uncomment) };
+    let middle = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("3").unwrap(), &options);
+    assert_eq!(middle, vec!["/*", " * a", " */", "b", "/*", " * c", " */"]);
+    let first = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("1,2").unwrap(), &options);
+    assert_eq!(first, vec!["a", "/*", " * b", " * c", " */"]);
+}
+
+#[test]
+fn prefix_is_matched_literally() {
+    let example = ["ab", ".b"].join("\n");
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &Options::new(".", CommentingMode::Uncomment));
+    assert_eq!(actual, vec!["ab", "b"]);
+}
+
+#[test]
+fn rejoin_joins_wrapped_comment_lines() {
+    let example = [
+        "    # This comment was hard-wrapped",
+        "    # over three lines",
+        "    #   by an editor.",
+        "    #",
+        "    # Second paragraph.",
+        "    x = 1",
+    ].join("\n");
+    let expected = vec![
+        "    This comment was hard-wrapped over three lines by an editor.",
+        "    #",
+        "    Second paragraph.",
+        "    x = 1",
+    ];
+    let options = Options { rejoin: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &try_parse_pattern("1,6").unwrap(), &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn rejoin_only_joins_former_comment_lines() {
+    assert_eq!(
+        rejoin_lines(vec!["a".to_string(), "b".to_string(), "c".to_string(), "d".to_string()], &[true, true, false, true]),
+        vec!["a b", "c", "d"],
+    );
+}
+
+#[test]
+fn indent_char_passes_through_other_indentation() {
+    let example = [
+        "\t# tab",
+        "  # space",
+        "# none",
+        " \t# mixed",
+    ].join("\n");
+    let pattern = AddressPattern::new_zero();
+    let cases = [
+        (IndentChar::Tab, vec!["\ttab", "  # space", "none", " \t# mixed"]),
+        (IndentChar::Space, vec!["\t# tab", "  space", "none", " \t# mixed"]),
+        (IndentChar::Any, vec!["\ttab", "  space", "none", " \tmixed"]),
+    ];
+    for (indent_char, expected) in cases.iter() {
+        let options = Options { indent_char: *indent_char, ..Options::new("# ", CommentingMode::Uncomment) };
+        let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+        assert_eq!(&actual, expected, "{:?}", indent_char);
+    }
+    // Commenting leaves the other indentation alone too, rather than adding a second marker
+    let options = Options { indent_char: IndentChar::Tab, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(["\tx", "  y"].join("\n").lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# \tx", "  y"]);
+}
+
+#[test]
+fn occurrence_changes_only_nth_block() {
+    let example = [
+        "start",
+        "a",
+        "end",
+        "x",
+        "start",
+        "b",
+        "end",
+        "y",
+        "start",
+        "c",
+        "end",
+    ].join("\n");
+    let expected = vec![
+        "start",
+        "a",
+        "end",
+        "x",
+        "# start",
+        "# b",
+        "# end",
+        "y",
+        "start",
+        "c",
+        "end",
+    ];
+    let pattern = try_parse_pattern("/start/,/end/").unwrap();
+    let options = Options { occurrence: Some(2), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    let options = Options { occurrence: Some(4), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+}
+
+#[test]
+fn occurrence_counts_touching_blocks_separately() {
+    let example = ["s", "1", "e", "s", "2", "e", "s", "3", "e"].join("\n");
+    let pattern = try_parse_pattern("/s/,/e/").unwrap();
+    let options = Options { occurrence: Some(2), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["s", "1", "e", "# s", "# 2", "# e", "s", "3", "e"]);
+
+    // Each block is toggled on its own, rather than the commented one deciding for the rest
+    let commented = actual.join("\n");
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# s", "# 1", "# e", "s", "2", "e", "# s", "# 3", "# e"]);
+
+    // A start matching again inside a `/re/,N` range stays in the same block
+    let pattern = try_parse_pattern("/s/,3").unwrap();
+    let options = Options { occurrence: Some(2), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body("s\ns\nx\ns".lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["s", "s", "x", "# s"]);
+}
+
+#[test]
+fn empty_comment_to_blank_leaves_empty_lines() {
+    let example = ["# ", "#", "   # ", "# x"].join("\n");
+    let pattern = AddressPattern::new_zero();
+    let options = Options { empty_comment_to_blank: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["", "", "", "x"]);
+    // Without the policy the indentation is left behind and a bare marker isn't a comment
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# ", CommentingMode::Uncomment));
+    assert_eq!(actual, vec!["", "#", "   ", "x"]);
+    let options = Options { empty_comment_to_blank: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(["   # ", "# y"].join("\n").lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["", "y"]);
+}
+
+#[test]
+fn sort_orders_commented_import_block() {
+    let example = [
+        "import sys",
+        "# import abc",
+        "import os",
+        "",
+        "from b import y",
+        "from a import x",
+        "main()",
+    ].join("\n");
+    let expected = vec![
+        "# # import abc",
+        "# import os",
+        "# import sys",
+        "",
+        "# from a import x",
+        "# from b import y",
+        "main()",
+    ];
+    let pattern = try_parse_pattern("1,6").unwrap();
+    let options = Options { sort: true, force: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    // Uncommented and still-commented lines sort together, equal keys keep their order
+    let example = ["# b", "a", "b", "# a"].join("\n");
+    let options = Options { sort: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["# a", "# a", "# b", "# b"]);
+}
+
+#[test]
+fn sort_ignores_stacked_markers() {
+    let example = ["# c", "a", "# b"].join("\n");
+    let pattern = try_parse_pattern("1,3").unwrap();
+    let options = Options { sort: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# a", "# # b", "# # c"]);
+    let options = Options { sort: true, force: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# a", "# # b", "# # c"]);
+}
+
+#[test]
+fn region_selects_lines_between_markers() {
+    let example = [
+        "a = 1",
+        "# BEGIN generated",
+        "b = 2",
+        "c = 3",
+        "# END generated",
+        "# BEGIN generated-extra",
+        "d = 4",
+        "# END generated-extra",
+        "# BEGIN generated",
+        "e = 5",
+    ].join("\n");
+    let expected = vec![
+        "a = 1",
+        "# BEGIN generated",
+        "# b = 2",
+        "# c = 3",
+        "# END generated",
+        "# BEGIN generated-extra",
+        "d = 4",
+        "# END generated-extra",
+        "# BEGIN generated",
+        "e = 5",
+    ];
+    let options = Options { region: Some(region_markers("generated")), ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, expected);
+    let (begin, end) = region_markers("generated");
+    let lines: Vec<&str> = example.lines().collect();
+    assert_eq!(region_lines(&lines, &begin, &end).1, Some(9));
+}
+
+#[test]
+fn stripe_selects_every_nth_line_from_offset() {
+    let example = ["1", "2", "3", "4", "5", "6", "7", "8"].join("\n");
+    let options = Options { stripe: Some((3, 1)), ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["# 1", "2", "3", "# 4", "5", "6", "# 7", "8"]);
+    let options = Options { stripe: Some((3, 0)), ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["1", "2", "# 3", "4", "5", "# 6", "7", "8"]);
+}
+
+#[test]
+fn prefix_expands_address_captures() {
+    let example = [
+        "login user=alice",
+        "noop",
+        "logout user=bob",
+    ].join("\n");
+    let expected = vec![
+        "# [alice] login user=alice",
+        "noop",
+        "# [bob] logout user=bob",
+    ];
+    let pattern = try_parse_pattern(r"/user=(\w+)/").unwrap();
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# [$1] ", CommentingMode::Toggle));
+    assert_eq!(actual, expected);
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("# [$1] ", CommentingMode::Toggle));
+    assert_eq!(actual.join("\n"), example);
+    let pattern = try_parse_pattern(r"/user=(?P<name>\w+)/").unwrap();
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &Options::new("// ${name}: ", CommentingMode::Comment));
+    assert_eq!(actual[0], "// alice: login user=alice");
+}
+
+#[test]
+fn any_of_selects_lines_containing_keywords() {
+    assert_eq!(try_parse_any_of(r"foo,a\,b,c\\d").unwrap(), vec!["foo", "a,b", r"c\d"]);
+    assert!(try_parse_any_of("foo,,bar").is_err());
+    assert!(try_parse_any_of(r"foo\").is_err());
+    let example = [
+        "call foo()",
+        "a.b()",
+        "x = a,b",
+        "bar + baz",
+        "qux",
+    ].join("\n");
+    let options = Options { any_of: try_parse_any_of(r"foo,a\,b,baz").unwrap(), ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["# call foo()", "a.b()", "# x = a,b", "# bar + baz", "qux"]);
+}
+
+#[test]
+fn plan_records_direction_per_block() {
+    let example = [
+        "start",
+        "# a",
+        "end",
+        "x",
+        "# start",
+        "# b",
+        "# end",
+    ].join("\n");
+    let lines: Vec<&str> = example.lines().collect();
+    let pattern = try_parse_pattern("/start/,/end/").unwrap();
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let chunks = select(&lines, EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(plan_blocks(&chunks, &pattern, &options), vec![
+        BlockPlan { start: 1, end: 3, direction: "comment" },
+        BlockPlan { start: 5, end: 7, direction: "uncomment" },
+    ]);
+    let options = Options { single_block: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let directions: Vec<&str> = plan_blocks(&chunks, &pattern, &options).iter().map(|b| b.direction).collect();
+    assert_eq!(directions, vec!["comment", "comment"]);
+    // Lines toggled one by one can go both ways within a block
+    let pattern = try_parse_pattern("/a|start/").unwrap();
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let chunks = select(&lines, EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(plan_blocks(&chunks, &pattern, &options), vec![
+        BlockPlan { start: 1, end: 2, direction: "mixed" },
+        BlockPlan { start: 5, end: 5, direction: "uncomment" },
+    ]);
+}
+
+#[test]
+fn mark_whitespace_replaces_only_whitespace_lines() {
+    let example = ["a", "", "   ", "\t", "b"].join("\n");
+    let options = Options { mark_whitespace: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual, vec!["# a", "", "# (whitespace)", "# (whitespace)", "# b"]);
+    let options = Options { mark_whitespace: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &AddressPattern::new_zero(), &options);
+    assert_eq!(actual.join("\n"), example);
+}
+
+#[test]
+fn adopt_prefix_uses_block_comment_marker() {
+    let example = [
+        "# python-ish header",
+        "",
+        "let a = 1;",
+        "// let b = 2;",
+        "let c = 3;",
+    ].join("\n");
+    let expected = vec![
+        "# python-ish header",
+        "",
+        "// let a = 1;",
+        "// // let b = 2;",
+        "// let c = 3;",
+    ];
+    let pattern = try_parse_pattern("3,5").unwrap();
+    let options = Options { adopt_prefix: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+    assert_eq!(block_marker(&["x", "  ; y", "// z"]), Some("; ".to_string()));
+    assert_eq!(block_marker(&["x", "#!y"]), None);
+}
+
+#[test]
+fn disable_then_enable_round_trips() {
+    let example = [
+        "[features]",
+        "# beta = true",
+        "beta = false",
+        "gamma = true",
+    ].join("\n");
+    let disabled = vec![
+        "[features]",
+        "# beta = true",
+        "# beta = false # disabled-by-toggle-comment",
+        "gamma = true",
+    ];
+    let pattern = try_parse_pattern("/beta/").unwrap();
+    let disable = Options { record_disabled: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &disable);
+    assert_eq!(actual, disabled);
+
+    // Only the line carrying the trailer comes back; the manual comment stays
+    let enable = Options { record_disabled: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &enable);
+    assert_eq!(actual.join("\n"), example);
+}
+
+#[test]
+fn nth_changes_nth_line_of_each_block() {
+    let example = [
+        "a1",
+        "",
+        "a1",
+        "a2",
+        "a3",
+        "",
+        "a1",
+        "a2",
+    ].join("\n");
+    let expected = vec![
+        "a1",
+        "",
+        "a1",
+        "# a2",
+        "a3",
+        "",
+        "a1",
+        "# a2",
+    ];
+    let pattern = try_parse_pattern("/a/").unwrap();
+    let options = Options { nth: Some(2), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+
+    let options = Options { nth: Some(4), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+}
+
+#[test]
+fn touching_blocks_stay_separate_for_nth_first_and_plan() {
+    let example = ["s", "1", "e", "s", "2", "e"].join("\n");
+    let pattern = try_parse_pattern("/s/,/e/").unwrap();
+    let options = Options { nth: Some(2), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["s", "# 1", "e", "s", "# 2", "e"]);
+
+    let options = Options { first_per_block: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# s", "1", "e", "# s", "2", "e"]);
+
+    let example = ["s", "1", "e", "# s", "# 2", "# e"].join("\n");
+    let lines: Vec<&str> = example.lines().collect();
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let chunks = select(&lines, EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(plan_blocks(&chunks, &pattern, &options), vec![
+        BlockPlan { start: 1, end: 3, direction: "comment" },
+        BlockPlan { start: 4, end: 6, direction: "uncomment" },
+    ]);
+}
+
+#[test]
+fn prefix_rules_pick_prefix_per_line() {
+    let example = [
+        "psql <<EOF",
+        "SELECT * FROM t;",
+        "EOF",
+        "echo done",
+    ].join("\n");
+    let expected = vec![
+        "// psql <<EOF",
+        "-- SELECT * FROM t;",
+        "// EOF",
+        "# echo done",
+    ];
+    let pattern = try_parse_pattern("1,4").unwrap();
+    let prefix_rules = vec![
+        try_parse_prefix_rule("/^SELECT/=-- ").unwrap(),
+        try_parse_prefix_rule("/^echo/=#").unwrap(),
+    ];
+    let options = Options { prefix_rules, ..Options::new("// ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+    assert!(try_parse_prefix_rule("^SELECT=--").is_err());
+    assert!(try_parse_prefix_rule("/^SELECT/=").is_err());
+}
+
+#[test]
+fn byte_range_maps_to_lines() {
+    // "héllo\n" is 7 bytes, as é takes two
+    let contents = "héllo\nab\n\nlast";
+    let lines = |start, end| {
+        let mut lines: Vec<usize> = byte_range_lines(contents, start, end).into_iter().collect();
+        lines.sort_unstable();
+        lines
+    };
+    assert_eq!(lines(0, 1), vec![1]);
+    assert_eq!(lines(2, 2), vec![1]);
+    assert_eq!(lines(6, 8), vec![1, 2]);
+    assert_eq!(lines(7, 10), vec![2]);
+    assert_eq!(lines(10, 11), vec![3]);
+    assert_eq!(lines(9, 100), vec![2, 3, 4]);
+    assert!(lines(100, 200).is_empty());
+    assert!(lines(usize::MAX, usize::MAX).is_empty());
+    assert_eq!(try_parse_byte_range("3..7").unwrap(), (3, 7));
+    assert!(try_parse_byte_range("7..3").is_err());
+    assert!(try_parse_byte_range("3-7").is_err());
+}
+
+#[test]
+fn literal_uncomment_keeps_rest_of_line() {
+    let example = [
+        "  # $head ${tail} # again",
+        "#\t$1",
+        "\t# keep  trailing  ",
+        "x = 1",
+    ].join("\n");
+    let expected = vec![
+        "  $head ${tail} # again",
+        "#\t$1",
+        "\tkeep  trailing  ",
+        "x = 1",
+    ];
+    let pattern = try_parse_pattern("1,4").unwrap();
+    let options = Options { literal_uncomment: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    let prefix_pattern = prefix_regex("# ", false, false);
+    assert_eq!(strip_marker(&prefix_pattern, " # a # b"), " a # b");
+    assert_eq!(strip_marker(&prefix_pattern, "a # b"), "a # b");
+}
+
+#[test]
+fn numbered_lines_parse_grep_output() {
+    let numbers = try_parse_numbered_lines("2:x = 1\n10:y: z\n\n").unwrap();
+    assert_eq!(numbers, [2, 10].iter().copied().collect());
+    assert!(try_parse_numbered_lines("x:2").is_err());
+}
+
+#[test]
+fn indent_block_round_trips_nested_mapping() {
+    let example = [
+        "services:",
+        "  web:",
+        "    image: nginx",
+        "    ports:",
+        "",
+        "      - 80",
+        "",
+        "  db:",
+        "    image: postgres",
+    ].join("\n");
+    let expected = vec![
+        "services:",
+        "#   web:",
+        "#     image: nginx",
+        "#     ports:",
+        "",
+        "#       - 80",
+        "",
+        "  db:",
+        "    image: postgres",
+    ];
+    let pattern = try_parse_pattern("/web:/").unwrap().indent_block().unwrap();
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    // The commented lines all start at column 0, only their uncommented indentation tells the block apart
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+}
+
+#[test]
+fn escape_existing_round_trips_nested_comments() {
+    let example = [
+        "x = 1",
+        "  # note",
+        r"\# literal",
+        "y = 2",
+    ].join("\n");
+    let expected = vec![
+        "# x = 1",
+        r"#   \# note",
+        r"# \\# literal",
+        "# y = 2",
+    ];
+    let pattern = try_parse_pattern("1,4").unwrap();
+    let options = Options { escape_existing: true, force: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+
+    let options = Options { escape_existing: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+}
+
+#[test]
+fn logical_lines_join_continuations() {
+    let example = [
+        "set -e",
+        r"docker run \",
+        r"    --rm \",
+        "    alpine true",
+        "echo done",
+    ].join("\n");
+    let expected = vec![
+        "set -e",
+        r"# docker run \",
+        r"#     --rm \",
+        "#     alpine true",
+        "echo done",
+    ];
+    let pattern = try_parse_pattern("/run.*--rm.*alpine/").unwrap();
+    let options = Options { logical_lines: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+
+    // Line addresses count logical lines
+    let pattern = try_parse_pattern("3").unwrap();
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.last().unwrap(), "# echo done");
+    let pattern = try_parse_pattern("/--rm/").unwrap();
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn remove_all_strips_stacked_markers() {
+    let example = [
+        "# # # x",
+        "  # # y",
+        "# z",
+    ].join("\n");
+    let pattern = try_parse_pattern("1,3").unwrap();
+    let options = Options::new("# ", CommentingMode::Uncomment);
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["# # x", "  # y", "z"]);
+
+    let options = Options { remove_all: true, ..Options::new("# ", CommentingMode::Uncomment) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["x", "  y", "z"]);
+}
+
+#[test]
+fn mixed_markers_finds_foreign_comments() {
+    let lines = vec![
+        "# config",
+        "",
+        "x = 1",
+        "# y = 2",
+        "// z = 3",
+        "; w = 4",
+        "",
+        "// outside",
+    ];
+    let pattern = try_parse_pattern("3,6").unwrap();
+    let options = Options::new("# ", CommentingMode::Toggle);
+    let chunks = select(&lines, EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(mixed_markers(&chunks, "# "), vec![(5, "//")]);
+    assert_eq!(mixed_markers(&chunks, "// "), vec![(4, "#")]);
+}
+
+#[test]
+fn pad_to_width_aligns_mixed_markers() {
+    let example = [
+        "doc: crate docs",
+        "let x = 1;",
+        "",
+        "let y = 2;",
+    ].join("\n");
+    let expected = vec![
+        "//! doc: crate docs",
+        "//  let x = 1;",
+        "",
+        "// let y = 2;",
+    ];
+    let pattern = try_parse_pattern("/./").unwrap();
+    let prefix_rules = vec![try_parse_prefix_rule("/^doc:/=//!").unwrap()];
+    let options = Options { prefix_rules, pad_to_width: true, ..Options::new("// ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+
+    let mut after = vec!["#: x".to_string(), "x".to_string(), "# y".to_string()];
+    pad_markers(&["x", "x", "y"], &mut after);
+    assert_eq!(after, vec!["#: x", "x", "#  y"]);
+}
+
+#[test]
+fn ed_script_escapes_lone_dot() {
+    let before = ["# .", "x", "# y"];
+    let after = [".", "x", "y"];
+    assert_eq!(ed_script(&before, &after), "3c\ny\n.\n1c\n..\n.\ns/^\\.//\nw\nq\n");
+    assert_eq!(ed_script(&before, &before), "w\nq\n");
+}
+
+#[test]
+fn diff_lines_from_hunks() {
+    let diff = "diff --git a/a.py b/a.py\n--- a/a.py\n+++ b/a.py\n@@ -2 +2 @@\n-b = 2\n+b = 20\n@@ -4,0 +5,2 @@ def f():\n+e\n+f\n@@ -7 +8,0 @@\n-g\n";
+    let mut lines: Vec<usize> = try_parse_diff_lines(diff).unwrap().into_iter().collect();
+    lines.sort_unstable();
+    assert_eq!(lines, vec![2, 5, 6]);
+}
+
+#[test]
+fn sentinels_round_trip() {
+    let example = [
+        "a",
+        "b",
+        "c",
+        "d",
+    ].join("\n");
+    let expected = vec![
+        "a",
+        "# <<< toggle-comment start",
+        "# b",
+        "# c",
+        "# >>> toggle-comment end",
+        "d",
+    ];
+    let pattern = try_parse_pattern("2,3").unwrap();
+    let options = Options { sentinels: true, ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+
+    // Commenting again leaves the sentinels alone
+    let commented = actual.join("\n");
+    let pattern = try_parse_pattern("/<<< toggle-comment start/,/>>> toggle-comment end/").unwrap();
+    let comment = Options { sentinels: true, ..Options::new("# ", CommentingMode::Comment) };
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &comment);
+    assert_eq!(actual, expected);
+
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+
+    // A commented block without sentinels doesn't gain them when uncommented
+    let pattern = try_parse_pattern("1,2").unwrap();
+    let actual = body("# a\n# b".lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, vec!["a", "b"]);
+}
+
+#[test]
+fn time_window_selects_by_leading_timestamp() {
+    let example = [
+        "2019-12-31 23:59:59 INFO last of the year",
+        "2020-01-01 00:00:00 INFO happy new year",
+        "    continuation without a time",
+        "2020-01-02 08:30:00 WARN disk filling",
+        "2020-01-03 12:00:00 ERROR disk full",
+    ].join("\n");
+    let expected = vec![
+        "2019-12-31 23:59:59 INFO last of the year",
+        "# 2020-01-01 00:00:00 INFO happy new year",
+        "    continuation without a time",
+        "# 2020-01-02 08:30:00 WARN disk filling",
+        "2020-01-03 12:00:00 ERROR disk full",
+    ];
+    let pattern = try_parse_pattern("").unwrap();
+    let time_window = TimeWindow::new("%Y-%m-%d", Some("2020-01-03"), Some("2019-12-31")).unwrap();
+    let options = Options { time_window: Some(time_window), ..Options::new("# ", CommentingMode::Toggle) };
+    let actual = body(example.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual, expected);
+    let commented = actual.join("\n");
+    let actual = body(commented.lines(), EMPTY_STATE.unchanged(), &pattern, &options);
+    assert_eq!(actual.join("\n"), example);
+
+    let window = TimeWindow::new("%Y-%m-%d %H:%M:%S", Some("2020-01-01 00:00:01"), None).unwrap();
+    assert!(window.matches("2020-01-01 00:00:00 INFO happy new year"));
+    assert!(!window.matches("2020-01-02 08:30:00 WARN disk filling"));
+    assert!(!window.matches("INFO no time"));
+    assert!(TimeWindow::new("%Y-%m-%d", Some("yesterday"), None).is_err());
+}
+
+#[test]
+fn widened_lines_only_counts_lines_pushed_over() {
+    let before = ["short", "123456789", "way too long already"];
+    let after = ["# short", "# 123456789", "# way too long already"];
+    assert_eq!(widened_lines(&before, &after, 10), vec![(2, 11)]);
+    assert_eq!(widened_lines(&before, &after, 30), vec![]);
+
+    let after = ["/*", "short", "123456789", "*/ long closing marker"];
+    assert_eq!(widened_lines(&before[..2], &after, 10), vec![(4, 22)]);
+}
@@ -0,0 +1,94 @@
+// Records of the files written in a run, so that they can be put back as they were with --undo.
+// Only the changed lines are kept, along with hashes to check that a file hasn't been edited
+// since, e.g.
+//
+// {"path":"a.py.new","hash":"…","original_hash":"…","changes":[{"line":2,"text":"y = 2"}]}
+
+use crate::{json::{self, Json}, ToggleError};
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust releases
+pub fn content_hash(contents: &str) -> String {
+    let hash = contents.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    format!("{:016x}", hash)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct UndoRecord {
+    pub path: String,
+    pub hash: String,                   // of the contents written to path
+    pub original_hash: String,          // of the contents before they were changed
+    pub changes: Vec<(usize, String)>,  // 1-indexed line numbers and their original text
+}
+
+impl UndoRecord {
+    pub fn new(path: &str, original: &str, written: &str) -> Result<UndoRecord, ToggleError> {
+        let before: Vec<&str> = original.lines().collect();
+        let after: Vec<&str> = written.lines().collect();
+        if before.len() != after.len() {
+            return Err(ToggleError::Usage("--log can't record added or removed lines".to_string()));
+        }
+        let changes = before.iter().zip(&after).enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(idx, (old, _))| (idx + 1, old.to_string()))
+            .collect();
+        Ok(UndoRecord { path: path.to_string(), hash: content_hash(written), original_hash: content_hash(original), changes })
+    }
+
+    pub fn to_json(&self) -> String {
+        let changes: Vec<String> = self.changes.iter()
+            .map(|(line, text)| format!(r#"{{"line":{},"text":{}}}"#, line, json::quote(text)))
+            .collect();
+        format!(
+            r#"{{"path":{},"hash":{},"original_hash":{},"changes":[{}]}}"#,
+            json::quote(&self.path), json::quote(&self.hash), json::quote(&self.original_hash), changes.join(","),
+        )
+    }
+
+    pub fn from_json(value: &Json) -> Result<UndoRecord, ToggleError> {
+        let field = |key| value.get(key).and_then(Json::as_str).map(str::to_string);
+        let (path, hash, original_hash) = match (field("path"), field("hash"), field("original_hash")) {
+            (Some(path), Some(hash), Some(original_hash)) => (path, hash, original_hash),
+            _ => return Err(ToggleError::Parse("malformed undo record".into())),
+        };
+        let changes = value.get("changes").and_then(Json::as_array).ok_or(ToggleError::Parse("malformed undo record".into()))?
+            .iter()
+            .map(|change| match (change.get("line").and_then(Json::as_usize), change.get("text").and_then(Json::as_str)) {
+                (Some(line), Some(text)) if line > 0 => Ok((line, text.to_string())),
+                _ => Err(ToggleError::Parse("malformed undo record".into())),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(UndoRecord { path, hash, original_hash, changes })
+    }
+
+    /// Put the original lines back into `current`, refusing if it was edited after being written
+    pub fn restore(&self, current: &str) -> Result<String, ToggleError> {
+        if content_hash(current) != self.hash {
+            return Err(ToggleError::Usage(format!("{}: changed since it was written, not undoing", self.path)));
+        }
+        let mut lines: Vec<&str> = current.lines().collect();
+        for (line, text) in &self.changes {
+            *lines.get_mut(line - 1).ok_or(ToggleError::Parse("undo record line out of range".into()))? = text;
+        }
+        let mut restored = lines.join("\n");
+        if current.ends_with('\n') {
+            restored.push('\n');
+        }
+        if content_hash(&restored) != self.original_hash {
+            return Err(ToggleError::Usage(format!("{}: undo doesn't reproduce the original, not undoing", self.path)));
+        }
+        Ok(restored)
+    }
+}
+
+/// Format records as the contents of a --log file
+pub fn log_to_json(records: &[UndoRecord]) -> String {
+    let records: Vec<String> = records.iter().map(UndoRecord::to_json).collect();
+    format!("[\n{}\n]\n", records.join(",\n"))
+}
+
+pub fn log_from_json(contents: &str) -> Result<Vec<UndoRecord>, ToggleError> {
+    json::parse(contents)?.as_array().ok_or(ToggleError::Parse("undo log must be a JSON array".into()))?
+        .iter()
+        .map(UndoRecord::from_json)
+        .collect()
+}
@@ -0,0 +1,1163 @@
+use std::process::Command;
+
+fn toggle_comment() -> Command {
+    let mut e = std::env::current_exe().unwrap();
+    e.pop(); // bin name
+    e.pop(); // deps/
+    e.push("toggle-comment");
+    Command::new(e)
+}
+
+#[test]
+fn double_dash_ends_option_parsing() {
+    let child = toggle_comment()
+        .arg("--mode").arg("comment")
+        .arg("--")
+        .arg("-3")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    let actual = String::from_utf8(child.stdout).unwrap();
+    let lines: Vec<&str> = actual.lines().collect();
+    assert_eq!(lines[6], "# How public, like a frog");
+    assert_eq!(lines.iter().filter(|l| l.starts_with("# ")).count(), 1);
+}
+
+#[test]
+fn max_regex_size_rejects_pathological_regex() {
+    let child = toggle_comment()
+        .arg("--max-regex-size").arg("1024")
+        .arg("/a{1000}{1000}/")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(!child.status.success());
+    assert!(child.stdout.is_empty());
+    let stderr = String::from_utf8(child.stderr).unwrap();
+    assert!(stderr.contains("size limit"), "unexpected stderr: {}", stderr);
+    assert!(!stderr.contains("panicked"), "unexpected stderr: {}", stderr);
+}
+
+#[test]
+fn max_regex_size_allows_small_regex() {
+    let child = toggle_comment()
+        .arg("--max-regex-size").arg("100000")
+        .arg("/nobody/")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+}
+
+#[test]
+fn directory_input_is_reported() {
+    let child = toggle_comment()
+        .arg("1")
+        .arg("examples")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(!child.status.success());
+    let stderr = String::from_utf8(child.stderr).unwrap();
+    assert!(stderr.contains("is a directory"), "unexpected stderr: {}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn named_pipe_input_is_streamed() {
+    use std::io::Write;
+
+    let fifo = std::env::temp_dir().join(format!("toggle-comment-fifo-{}", std::process::id()));
+    let _ = std::fs::remove_file(&fifo);
+    let status = Command::new("mkfifo").arg(&fifo).status().expect("Failed to spawn mkfifo");
+    assert!(status.success());
+
+    let child = toggle_comment()
+        .arg("2")
+        .arg(&fifo)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("Failed to start toggle-comment");
+    std::fs::OpenOptions::new().write(true).open(&fifo).unwrap()
+        .write_all(b"one\ntwo\nthree\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&fifo).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "one\n# two\nthree\n");
+}
+
+#[test]
+fn stats_summary_format() {
+    let child = toggle_comment()
+        .arg("--stats")
+        .arg("/nobody/,/somebody/")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stderr).unwrap(), "lines=9 matched=6 changed=6 mode=toggle\n");
+}
+
+#[test]
+fn stats_reports_script_mode() {
+    let child = toggle_comment()
+        .arg("--stats")
+        .arg("--script").arg("1 comment; 2 uncomment")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stderr).unwrap(), "lines=9 matched=2 changed=1 mode=script\n");
+}
+
+#[test]
+fn empty_first_address_matches_from_line_one() {
+    let run = |pattern: &str| {
+        toggle_comment()
+            .arg(pattern)
+            .arg("examples/poem.txt")
+            .output()
+            .expect("Failed to start toggle-comment")
+            .stdout
+    };
+    assert_eq!(run(",5"), run("1,5"));
+}
+
+#[test]
+fn hunk_header_takes_place_of_pattern() {
+    let child = toggle_comment()
+        .arg("--hunk").arg("@@ -1,2 +2,3 @@")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    let actual = String::from_utf8(child.stdout).unwrap();
+    let commented: Vec<usize> = actual.lines().enumerate()
+        .filter(|(_, l)| l.starts_with("# "))
+        .map(|(i, _)| i + 1)
+        .collect();
+    assert_eq!(commented, vec![2, 3, 4]);
+}
+
+#[test]
+fn prefix_detected_from_existing_comments() {
+    let child = toggle_comment()
+        .arg("3")
+        .arg("examples/hello.rs")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    let actual = String::from_utf8(child.stdout).unwrap();
+    assert_eq!(actual.lines().nth(2), Some("//     println!(\"Hello, world!\");"));
+}
+
+#[test]
+fn suffix_out_writes_one_file_per_input() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-suffix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (first, second) = (dir.join("first.py"), dir.join("second.py"));
+    std::fs::write(&first, "a = 1\nb = 2\n").unwrap();
+    std::fs::write(&second, "c = 3\nd = 4\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("--suffix-out").arg(".commented")
+        .arg("2")
+        .arg(&first)
+        .arg(&second)
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    assert!(child.stdout.is_empty());
+    assert_eq!(std::fs::read_to_string(dir.join("first.py.commented")).unwrap(), "a = 1\n# b = 2\n");
+    assert_eq!(std::fs::read_to_string(dir.join("second.py.commented")).unwrap(), "c = 3\n# d = 4\n");
+    assert_eq!(std::fs::read_to_string(&first).unwrap(), "a = 1\nb = 2\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn write_matched_collects_original_lines() {
+    let side = std::env::temp_dir().join(format!("toggle-comment-matched-{}", std::process::id()));
+
+    let child = toggle_comment()
+        .arg("--write-matched").arg(&side)
+        .arg("/nobody/")
+        .arg("examples/poem.txt")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    let stdout = String::from_utf8(child.stdout).unwrap();
+    assert!(stdout.starts_with("# I’m nobody! Who are you?\n# Are you nobody, too?\n"));
+    let matched = std::fs::read_to_string(&side).unwrap();
+    std::fs::remove_file(&side).unwrap();
+    assert_eq!(matched, "I’m nobody! Who are you?\nAre you nobody, too?\n".repeat(2));
+}
+
+#[test]
+fn context_separator_splits_distant_blocks() {
+    let side = std::env::temp_dir().join(format!("toggle-comment-separated-{}", std::process::id()));
+    let side_arg = side.to_str().unwrap();
+
+    let input = "a1\na2\nb\nc\na3\nd\n";
+    let stdout = run_on_stdin(&["--write-matched", side_arg, "--context-separator", "/a/"], input);
+    assert_eq!(stdout, "# a1\n# a2\nb\nc\n# a3\nd\n");
+    let matched = std::fs::read_to_string(&side).unwrap();
+    std::fs::remove_file(&side).unwrap();
+    assert_eq!(matched, "a1\na2\n--\na3\n");
+}
+
+#[test]
+fn dump_ast_prints_parsed_pattern() {
+    let child = toggle_comment()
+        .arg("--dump-ast")
+        .arg("3,/end/")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    let dump = String::from_utf8(child.stdout).unwrap();
+    assert!(dump.contains("AddressPattern"));
+    assert!(dump.contains("AddressRange"));
+    assert!(dump.contains("Line(\n"));
+    assert!(dump.contains("RegexPattern("));
+    assert!(dump.contains("negated: false"));
+}
+
+#[test]
+fn line_buffered_flushes_each_line_before_eof() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::process::Stdio;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let mut child = toggle_comment()
+        .arg("--line-buffered")
+        .arg("--mode").arg("comment")
+        .arg("/ERROR/")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start toggle-comment");
+
+    let mut stdin = child.stdin.take().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            tx.send(line.unwrap()).unwrap();
+        }
+    });
+
+    // Each line must come back while stdin is still open
+    for (given, expected) in [("INFO: started", "INFO: started"), ("ERROR: failed", "# ERROR: failed")].iter() {
+        writeln!(stdin, "{}", given).unwrap();
+        stdin.flush().unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)).unwrap(), *expected);
+    }
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn line_buffered_rejects_lookahead_patterns() {
+    let child = toggle_comment()
+        .arg("--line-buffered")
+        .arg("1,-1")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert_eq!(child.status.code(), Some(2));
+}
+
+#[test]
+fn line_buffered_rejects_whole_output_options() {
+    let path = std::env::temp_dir().join(format!("toggle-comment-line-buffered-{}", std::process::id()));
+    let write_matched = toggle_comment().arg("--line-buffered").arg("--write-matched").arg(&path).arg("1").output().unwrap();
+    let gutter = toggle_comment().args(["--line-buffered", "--gutter", "1"]).output().unwrap();
+    let check = toggle_comment().args(["--line-buffered", "--check", "1"]).output().unwrap();
+
+    assert!(!write_matched.status.success());
+    assert!(!path.exists());
+    assert!(!gutter.status.success());
+    assert!(!check.status.success());
+}
+
+#[test]
+fn verbose_warns_about_inverted_range() {
+    let child = toggle_comment()
+        .arg("--verbose")
+        .arg("7,3")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), std::fs::read_to_string("examples/poem.txt").unwrap());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("range ends before it starts"));
+}
+
+#[test]
+fn quiet_silences_warnings() {
+    let child = toggle_comment()
+        .arg("--quiet")
+        .arg("--verbose")
+        .arg("--stats")
+        .arg("7,3")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), std::fs::read_to_string("examples/poem.txt").unwrap());
+    assert_eq!(String::from_utf8(child.stderr).unwrap(), "");
+}
+
+#[test]
+fn byte_range_selects_overlapping_lines() {
+    let stdout = run_on_stdin(&["--byte-range", "8..11"], "naïve\nb\nc\nd\n");
+    assert_eq!(stdout, "naïve\n# b\n# c\nd\n");
+}
+
+#[test]
+fn byte_range_past_the_input_is_an_error() {
+    let child = toggle_comment().args(["--byte-range", "1000..2000", "examples/poem.txt"]).output().unwrap();
+    assert_eq!(child.status.code(), Some(2));
+    assert!(child.stdout.is_empty());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("past the end"));
+    assert_eq!(run_on_stdin(&["--byte-range", "2..2"], "ab\ncd\n"), "# ab\ncd\n");
+}
+
+#[test]
+fn filter_cmd_selects_numbered_lines() {
+    let stdout = run_on_stdin(&["-c", "# ", "--filter-cmd", "grep -n TODO"], "a\n// TODO: b\nc\nTODO d\n");
+    assert_eq!(stdout, "a\n# // TODO: b\nc\n# TODO d\n");
+    let stdout = run_on_stdin(&["--filter-cmd", "grep -n nothing"], "a\nb\n");
+    assert_eq!(stdout, "a\nb\n");
+
+    let child = toggle_comment()
+        .args(["--filter-cmd", "exit 3", "examples/poem.txt"])
+        .output()
+        .expect("Failed to start toggle-comment");
+    assert!(!child.status.success());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("filter command \"exit 3\" failed"));
+}
+
+#[test]
+fn encoding_round_trips_latin1() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    // "café = 1" and "naïve = 2" in Latin-1, which isn't valid UTF-8
+    let input = b"caf\xe9 = 1\nna\xefve = 2\n";
+    let mut child = toggle_comment()
+        .args(["-c", "# ", "--encoding", "latin1", "/caf/"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start toggle-comment");
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"# caf\xe9 = 1\nna\xefve = 2\n".to_vec());
+
+    let child = toggle_comment()
+        .args(["--encoding", "klingon", "1", "examples/poem.txt"])
+        .output()
+        .expect("Failed to start toggle-comment");
+    assert!(!child.status.success());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("unknown encoding"));
+}
+
+#[test]
+fn max_width_warns_about_lines_pushed_over() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let run = |args: &[&str]| {
+        let mut child = toggle_comment()
+            .args(["-c", "# ", "--max-width", "10"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to start toggle-comment");
+        child.stdin.take().unwrap().write_all(b"short\n123456789\nway too long already\n").unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    let warned = run(&["1,3"]);
+    assert!(warned.status.success());
+    assert_eq!(String::from_utf8(warned.stdout).unwrap(), "# short\n# 123456789\n# way too long already\n");
+    let stderr = String::from_utf8(warned.stderr).unwrap();
+    assert_eq!(stderr, "toggle-comment: warning: <stdin>:2: line is 11 characters wide, over --max-width 10\n", "unexpected stderr: {}", stderr);
+
+    let strict = run(&["--strict-width", "1,3"]);
+    assert_eq!(strict.status.code(), Some(1));
+    assert!(strict.stdout.is_empty());
+    assert!(String::from_utf8(strict.stderr).unwrap().ends_with("toggle-comment: 1 line would be over --max-width\n"));
+
+    let streamed = toggle_comment().args(["--max-width", "10", "--strict-width", "--line-buffered", "1,3"]).output().unwrap();
+    assert!(!streamed.status.success());
+}
+
+#[test]
+fn completions_prints_bash_script() {
+    let child = toggle_comment()
+        .arg("completions")
+        .arg("bash")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    let script = String::from_utf8(child.stdout).unwrap();
+    assert!(!script.is_empty());
+    assert!(script.contains("--mode"));
+}
+
+#[test]
+fn editorconfig_comment_prefix_is_used() {
+    let root = std::env::temp_dir().join(format!("toggle-comment-editorconfig-{}", std::process::id()));
+    let nested = root.join("src");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::write(root.join(".editorconfig"), "root = true\n\n[*]\nindent_style = space\n\n[*.{c,h}]\ncomment_prefix = \"// \"\n").unwrap();
+    std::fs::write(nested.join("main.c"), "int x;\n# not a comment\n").unwrap();
+    std::fs::write(nested.join("notes.txt"), "plain\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("1")
+        .arg(nested.join("main.c"))
+        .arg(nested.join("notes.txt"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), "// int x;\n# not a comment\n# plain\n");
+}
+
+#[test]
+fn gutter_numbers_output_lines() {
+    let child = toggle_comment()
+        .arg("--gutter")
+        .arg("2")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    let actual = String::from_utf8(child.stdout).unwrap();
+    let lines: Vec<&str> = actual.lines().collect();
+    assert_eq!(lines[0], "1 | I’m nobody! Who are you?");
+    assert_eq!(lines[1], "2 | # Are you nobody, too?");
+    assert!(lines.last().unwrap().starts_with(&format!("{} | ", lines.len())));
+}
+
+#[test]
+fn gutter_never_reaches_edited_files() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-gutter-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a.py");
+    std::fs::write(&path, "x = 1\ny = 2\n").unwrap();
+
+    let in_place = toggle_comment().args(["-i", "--gutter", "2"]).arg(&path).output().unwrap();
+    let check = toggle_comment().args(["--check", "--gutter", "2"]).arg(&path).output().unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!in_place.status.success());
+    assert!(!check.status.success());
+    assert_eq!(contents, "x = 1\ny = 2\n");
+}
+
+#[test]
+fn baseline_comments_lines_changed_from_old_file() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-baseline-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("old.txt"), "one\ntwo\nthree\n").unwrap();
+    std::fs::write(dir.join("new.txt"), "one\n2\nthree\nfour\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("--mode").arg("comment")
+        .arg("--baseline").arg(dir.join("old.txt"))
+        .arg(dir.join("new.txt"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), "one\n# 2\nthree\n# four\n");
+}
+
+#[test]
+fn prefix_map_picks_prefix_per_extension() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-prefix-map-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("main.rs"), "let x = 1;\n").unwrap();
+    std::fs::write(dir.join("main.py"), "x = 1\n").unwrap();
+    std::fs::write(dir.join("query.sql"), "select 1;\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("--prefix-map").arg("rs=//")
+        .arg("--prefix-map").arg("sql=--")
+        .arg("1")
+        .arg(dir.join("main.rs"))
+        .arg(dir.join("main.py"))
+        .arg(dir.join("query.sql"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), "// let x = 1;\n# x = 1\n-- select 1;\n");
+}
+
+#[test]
+fn binary_inputs_are_skipped() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-binary-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("text.txt"), "hello\n").unwrap();
+    std::fs::write(dir.join("data.bin"), b"ELF\0\x01\x02\n").unwrap();
+
+    let skipped = toggle_comment()
+        .arg("1")
+        .arg(dir.join("data.bin"))
+        .arg(dir.join("text.txt"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    let forced = toggle_comment()
+        .arg("--binary")
+        .arg("1")
+        .arg(dir.join("data.bin"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    let single = toggle_comment()
+        .arg("1")
+        .arg(dir.join("data.bin"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(skipped.status.success());
+    assert_eq!(String::from_utf8(skipped.stdout).unwrap(), "# hello\n");
+    assert!(String::from_utf8(skipped.stderr).unwrap().contains("skipping binary file"));
+    assert!(forced.status.success());
+    assert_eq!(forced.stdout, b"# ELF\0\x01\x02\n");
+    // A single named file is refused rather than skipped without any output
+    assert_eq!(single.status.code(), Some(2));
+    assert!(single.stdout.is_empty());
+    assert!(String::from_utf8(single.stderr).unwrap().contains("--binary"));
+}
+
+#[test]
+fn check_passes_when_already_commented() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-check-ok-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("done.py"), "x = 1\n# y = 2\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("--check")
+        .arg("--mode").arg("comment")
+        .arg("2")
+        .arg(dir.join("done.py"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(child.status.code(), Some(0));
+    assert!(child.stdout.is_empty());
+}
+
+#[test]
+fn check_fails_and_lists_lines_that_would_change() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-check-fail-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("todo.py");
+    std::fs::write(&path, "x = 1\ny = 2\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("--check")
+        .arg("--mode").arg("comment")
+        .arg("2")
+        .arg(&path)
+        .output()
+        .expect("Failed to start toggle-comment");
+    let untouched = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(child.status.code(), Some(1));
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), format!("{}:2\n-y = 2\n+# y = 2\n", path.display()));
+    assert!(String::from_utf8(child.stderr).unwrap().contains("1 line would be changed"));
+    assert_eq!(untouched, "x = 1\ny = 2\n");
+}
+
+#[test]
+fn warns_when_prefix_conflicts_with_shebang() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-shebang-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("script"), "#!/usr/bin/env python3\nprint('hi')\n").unwrap();
+
+    let mismatched = toggle_comment()
+        .arg("-c").arg("// ")
+        .arg("2")
+        .arg(dir.join("script"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    let matched = toggle_comment()
+        .arg("-c").arg("# ")
+        .arg("2")
+        .arg(dir.join("script"))
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(mismatched.status.success());
+    assert_eq!(String::from_utf8(mismatched.stdout).unwrap(), "#!/usr/bin/env python3\n// print('hi')\n");
+    assert!(String::from_utf8(mismatched.stderr).unwrap().contains("may be wrong"));
+    assert!(matched.stderr.is_empty());
+}
+
+#[test]
+fn ed_script_reproduces_changes() {
+    let stdout = run_on_stdin(&["-c", "# ", "--ed-script", "2,3"], "a\nb\nc\nd\n");
+    assert_eq!(stdout, "3c\n# c\n.\n2c\n# b\n.\nw\nq\n");
+}
+
+#[test]
+fn keep_going_carries_on_past_failed_files() {
+    let missing = "examples/no-such-file.txt";
+    let child = toggle_comment()
+        .args(["--keep-going", "1", "examples/poem.txt", missing, "examples/poem.txt"])
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert_eq!(child.status.code(), Some(1));
+    let stdout = String::from_utf8(child.stdout).unwrap();
+    assert_eq!(stdout.matches("# I’m nobody! Who are you?\n").count(), 2);
+    let stderr = String::from_utf8(child.stderr).unwrap();
+    assert!(stderr.contains(missing), "unexpected stderr: {}", stderr);
+    assert!(stderr.ends_with("toggle-comment: 1 file failed\n"), "unexpected stderr: {}", stderr);
+
+    let child = toggle_comment()
+        .args(["1", missing, "examples/poem.txt"])
+        .output()
+        .expect("Failed to start toggle-comment");
+    assert!(!child.status.success());
+    assert!(child.stdout.is_empty());
+}
+
+#[test]
+fn lsp_edits_prints_json_text_edits() {
+    let child = toggle_comment()
+        .arg("--lsp-edits")
+        .arg("2")
+        .arg("examples/poem.txt")
+        .output()
+        .expect("Failed to start toggle-comment");
+
+    assert!(child.status.success());
+    assert_eq!(
+        String::from_utf8(child.stdout).unwrap(),
+        "[{\"range\":{\"start\":{\"line\":1,\"character\":0},\"end\":{\"line\":1,\"character\":0}},\"newText\":\"# \"}]\n",
+    );
+}
+
+#[test]
+fn lsp_edits_conflicts_with_suffix_out() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-lsp-suffix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a.py");
+    std::fs::write(&path, "x = 1\n").unwrap();
+    let child = toggle_comment().args(["--lsp-edits", "--suffix-out", ".out", "1"]).arg(&path).output().unwrap();
+    let written = dir.join("a.py.out").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!child.status.success());
+    assert!(child.stdout.is_empty());
+    assert!(!written);
+}
+
+#[test]
+fn check_conflicts_with_suffix_out() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-check-suffix-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a.py");
+    std::fs::write(&path, "x = 1\n").unwrap();
+    let child = toggle_comment().args(["--check", "--suffix-out", ".out", "1"]).arg(&path).output().unwrap();
+    let written = dir.join("a.py.out").exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!child.status.success());
+    assert!(child.stdout.is_empty());
+    assert!(!written);
+}
+
+#[test]
+fn script_steps_apply_in_order() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-script-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("input.py"), "a = 1\nTODO = 2\nc = 3\n").unwrap();
+
+    let run = |script: &str| {
+        let child = toggle_comment()
+            .arg("--script").arg(script)
+            .arg(dir.join("input.py"))
+            .output()
+            .expect("Failed to start toggle-comment");
+        assert!(child.status.success());
+        String::from_utf8(child.stdout).unwrap()
+    };
+    let comment_then_uncomment = run("1,3 comment; /TODO/ uncomment");
+    let uncomment_then_comment = run("/TODO/ uncomment; 1,3 comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(comment_then_uncomment, "# a = 1\nTODO = 2\n# c = 3\n");
+    assert_eq!(uncomment_then_comment, "# a = 1\n# TODO = 2\n# c = 3\n");
+}
+
+fn run_on_stdin(args: &[&str], input: &str) -> String {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = toggle_comment()
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Failed to start toggle-comment");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn trailing_blank_lines_are_preserved_exactly() {
+    for &mode in ["comment", "toggle", "uncomment"].iter() {
+        let expected = if mode == "uncomment" { "a\n\n" } else { "# a\n\n" };
+        assert_eq!(run_on_stdin(&["--mode", mode, "1,2"], "a\n\n"), expected);
+        assert_eq!(run_on_stdin(&["--mode", mode, ""], "a\n\n"), expected);
+        let expected = if mode == "uncomment" { "a\n   \n" } else { "# a\n   \n" };
+        assert_eq!(run_on_stdin(&["--mode", mode, ""], "a\n   \n"), expected);
+        let expected = if mode == "uncomment" { "a\n   " } else { "# a\n   " };
+        assert_eq!(run_on_stdin(&["--mode", mode, ""], "a\n   "), expected);
+    }
+    assert_eq!(run_on_stdin(&["1"], "a"), "# a");
+}
+
+#[test]
+fn log_then_undo_restores_written_files() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-undo-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("a.py");
+    let output = dir.join("a.py.new");
+    let log = dir.join("undo.json");
+    std::fs::write(&input, "x = 1\ny = 2\n").unwrap();
+
+    let edit = toggle_comment()
+        .arg("--suffix-out").arg(".new")
+        .arg("--log").arg(&log)
+        .arg("2")
+        .arg(&input)
+        .output()
+        .expect("Failed to start toggle-comment");
+    assert!(edit.status.success());
+    assert_eq!(std::fs::read_to_string(&output).unwrap(), "x = 1\n# y = 2\n");
+
+    let undo = toggle_comment()
+        .arg("--undo").arg(&log)
+        .output()
+        .expect("Failed to start toggle-comment");
+    let restored = std::fs::read_to_string(&output).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(undo.status.success());
+    assert_eq!(restored, "x = 1\ny = 2\n");
+}
+
+#[test]
+fn log_records_files_written_before_a_failure() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-undo-failure-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (a, missing) = (dir.join("a.py"), dir.join("missing.py"));
+    let log = dir.join("undo.json");
+    std::fs::write(&a, "x = 1\n").unwrap();
+
+    let edit = toggle_comment().arg("-i").arg("--log").arg(&log).arg("1").arg(&a).arg(&missing).output().unwrap();
+    let edited = std::fs::read_to_string(&a).unwrap();
+    let undo = toggle_comment().arg("--undo").arg(&log).output().unwrap();
+    let restored = std::fs::read_to_string(&a).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(edit.status.code(), Some(1));
+    assert_eq!(edited, "# x = 1\n");
+    assert!(undo.status.success());
+    assert_eq!(restored, "x = 1\n");
+}
+
+#[test]
+fn parallel_log_records_every_worker_before_a_failure() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-undo-parallel-failure-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths: Vec<_> = (0..8).map(|i| dir.join(format!("{}.py", i))).collect();
+    for path in &paths {
+        std::fs::write(path, "x = 1\n").unwrap();
+    }
+    let log = dir.join("undo.json");
+
+    let edit = toggle_comment().args(["-i", "--parallel", "4", "--log"]).arg(&log).arg("1")
+        .args(&paths[..4]).arg(dir.join("missing.py")).args(&paths[4..]).output().unwrap();
+    let undo = toggle_comment().arg("--undo").arg(&log).output().unwrap();
+    let restored: Vec<String> = paths.iter().map(|path| std::fs::read_to_string(path).unwrap()).collect();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(edit.status.code(), Some(1));
+    assert!(undo.status.success());
+    assert!(restored.iter().all(|contents| contents == "x = 1\n"), "{:?}", restored);
+}
+
+#[test]
+fn log_rejects_options_adding_or_removing_lines() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-log-reject-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let a = dir.join("a.py");
+    std::fs::write(&a, "x = 1\ny = 2\n").unwrap();
+    let log = dir.join("undo.json");
+    let rejected: Vec<_> = [&["--sentinels"][..], &["--prepend", "z"], &["--append", "z"], &["--squeeze"], &["--rejoin"], &["--marker-style", "block-c"]].iter()
+        .map(|extra| toggle_comment().arg("-i").arg("--log").arg(&log).args(*extra).arg("1").arg(&a).output().unwrap())
+        .collect();
+    let untouched = std::fs::read_to_string(&a).unwrap();
+    let logged = log.exists();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    for output in rejected {
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--log"));
+    }
+    assert_eq!(untouched, "x = 1\ny = 2\n");
+    assert!(!logged);
+}
+
+#[test]
+fn log_then_undo_restores_files_edited_in_place() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-undo-in-place-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (a, b) = (dir.join("a.py"), dir.join("b.py"));
+    let log = dir.join("undo.json");
+    std::fs::write(&a, "x = 1\ny = 2\n").unwrap();
+    std::fs::write(&b, "z = 3\n").unwrap();
+
+    let edit = toggle_comment().arg("-i").arg("--log").arg(&log).arg("1").arg(&a).output().unwrap();
+    let edited = std::fs::read_to_string(&a).unwrap();
+    let undo = toggle_comment().arg("--undo").arg(&log).output().unwrap();
+    let restored = std::fs::read_to_string(&a).unwrap();
+    let parallel = toggle_comment().args(["-i", "--parallel", "2", "--log"]).arg(&log).arg("1").arg(&a).arg(&b).output().unwrap();
+    let undo_parallel = toggle_comment().arg("--undo").arg(&log).output().unwrap();
+    let restored_both = (std::fs::read_to_string(&a).unwrap(), std::fs::read_to_string(&b).unwrap());
+    let unwritten = toggle_comment().arg("--log").arg(&log).arg("1").arg(&a).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(edit.status.success());
+    assert_eq!(edited, "# x = 1\ny = 2\n");
+    assert!(undo.status.success());
+    assert_eq!(restored, "x = 1\ny = 2\n");
+    assert!(parallel.status.success());
+    assert!(undo_parallel.status.success());
+    assert_eq!(restored_both, ("x = 1\ny = 2\n".to_string(), "z = 3\n".to_string()));
+    assert_eq!(unwritten.status.code(), Some(2));
+}
+
+#[test]
+fn squeeze_composes_with_commenting() {
+    assert_eq!(run_on_stdin(&["--squeeze", "--mode", "comment", "1"], "a\n\n\nb\n\n"), "# a\n\nb\n\n");
+    assert_eq!(run_on_stdin(&["--trim-blank-run", "--append", "", "1"], "a\n\nb\n"), "# a\n\nb\n");
+}
+
+#[test]
+fn block_by_indent_selects_the_indented_block() {
+    let input = "server:\n  host: a\n  port: 1\nclient:\n  host: b\n";
+    let expected = "# server:\n#   host: a\n#   port: 1\nclient:\n  host: b\n";
+    assert_eq!(run_on_stdin(&["--block-by-indent", "1"], input), expected);
+    assert_eq!(run_on_stdin(&["--indent-block", "1"], input), expected);
+}
+
+#[test]
+fn line_buffered_rejects_input_files() {
+    let child = toggle_comment().args(["--line-buffered", "1", "examples/poem.txt"]).output().unwrap();
+    assert_eq!(child.status.code(), Some(2));
+    assert!(child.stdout.is_empty());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("--line-buffered"));
+}
+
+#[test]
+fn squeeze_is_rejected_when_line_buffered() {
+    let child = toggle_comment().args(["--squeeze", "--line-buffered", "1"]).output().unwrap();
+    assert!(!child.status.success());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("--line-buffered"));
+}
+
+#[test]
+fn addr_sep_changes_the_address_separator() {
+    assert_eq!(run_on_stdin(&["--addr-sep", ":", "/a,b/:3"], "x\na,b\ny\nz\n"), "x\n# a,b\n# y\nz\n");
+    assert_eq!(run_on_stdin(&["/a,b/,3"], "x\na,b\ny\nz\n"), "x\n# a,b\n# y\nz\n");
+    assert!(!toggle_comment().args(["--addr-sep", "/", "1/2"]).output().unwrap().status.success());
+}
+
+#[test]
+fn preview_width_truncates_check_listing_only() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-preview-width-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("long.py");
+    std::fs::write(&path, "value = 12345\nok\n").unwrap();
+
+    let child = toggle_comment()
+        .arg("--check")
+        .arg("--preview-width").arg("8")
+        .arg("/./")
+        .arg(&path)
+        .output()
+        .expect("Failed to start toggle-comment");
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(child.status.code(), Some(1));
+    let expected = format!("{0}:1\n-value =…\n+# value…\n{0}:2\n-ok\n+# ok\n", path.display());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), expected);
+    assert!(String::from_utf8(child.stderr).unwrap().contains("2 lines would be changed"));
+}
+
+#[test]
+fn lines_from_stdin_selects_lines_of_input_file() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-lines-from-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("lines.py");
+    std::fs::write(&path, "a\nb\nc\nd\n").unwrap();
+
+    let output = run_on_stdin(&["--lines-from", "-", path.to_str().unwrap()], "2\n4\n");
+    let missing_input = toggle_comment().args(["--lines-from", "-"]).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(output, "a\n# b\nc\n# d\n");
+    assert_eq!(missing_input.status.code(), Some(2));
+}
+
+#[test]
+fn marker_style_block_c_wraps_and_unwraps() {
+    let commented = run_on_stdin(&["--marker-style", "block-c", "2,3"], "int a;\nint b;\nint c;\n");
+    assert_eq!(commented, "int a;\n/*\n * int b;\n * int c;\n */\n");
+    assert_eq!(run_on_stdin(&["--marker-style", "block-c", "2,5"], &commented), "int a;\nint b;\nint c;\n");
+}
+
+#[test]
+fn region_warns_about_missing_end_marker() {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = toggle_comment()
+        .args(["--region", "X"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to start toggle-comment");
+    child.stdin.take().unwrap().write_all(b"a\n# BEGIN X\nb\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "a\n# BEGIN X\nb\n");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("<stdin>:2: region \"X\" is never closed"));
+}
+
+#[test]
+fn ensure_trailing_newline_normalizes_final_terminator() {
+    for input in ["a\nb", "a\nb\n", "a\nb\n\n"].iter() {
+        assert_eq!(run_on_stdin(&["--ensure-trailing-newline", "1"], input), "# a\nb\n", "{:?}", input);
+    }
+    assert_eq!(run_on_stdin(&["1"], "a\nb"), "# a\nb");
+    assert_eq!(run_on_stdin(&["1"], "a\nb\n\n"), "# a\nb\n\n");
+}
+
+#[test]
+fn stripe_needs_no_pattern() {
+    assert_eq!(run_on_stdin(&["--stripe", "3", "--stripe-offset", "1"], "a\nb\nc\nd\ne\nf\ng\n"), "# a\nb\nc\n# d\ne\nf\n# g\n");
+    assert_eq!(run_on_stdin(&["--stripe", "2"], "a\nb\nc\n"), "# a\nb\n# c\n");
+}
+
+#[test]
+fn global_toggle_moves_every_block_the_same_way() {
+    let input = "# BEGIN\n# a\n# END\n\nBEGIN\nb\nEND\n";
+    // By default each block picks its own direction, so the commented one is uncommented
+    assert_eq!(run_on_stdin(&["/BEGIN/,/END/"], input), "BEGIN\na\nEND\n\n# BEGIN\n# b\n# END\n");
+    assert_eq!(run_on_stdin(&["--global-toggle", "/BEGIN/,/END/"], input), "# # BEGIN\n# # a\n# # END\n\n# BEGIN\n# b\n# END\n");
+}
+
+#[test]
+fn in_place_replaces_input() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-in-place-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("a.py");
+    std::fs::write(&path, "x = 1\ny = 2\n").unwrap();
+
+    let child = toggle_comment().arg("-i").arg("2").arg(&path).output().unwrap();
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let leftovers = std::fs::read_dir(&dir).unwrap().count();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(child.status.success());
+    assert!(child.stdout.is_empty());
+    assert_eq!(contents, "x = 1\n# y = 2\n");
+    assert_eq!(leftovers, 1);
+}
+
+#[test]
+fn parallel_edits_many_files() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-parallel-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let paths: Vec<_> = (0..64).map(|n| {
+        let path = dir.join(format!("{}.py", n));
+        std::fs::write(&path, format!("x = {}\ny = {}\n", n, n)).unwrap();
+        path
+    }).collect();
+
+    let child = toggle_comment().args(["-i", "--parallel", "4", "--stats", "2"]).args(&paths).output().unwrap();
+    let contents: Vec<String> = paths.iter().map(|path| std::fs::read_to_string(path).unwrap()).collect();
+    let leftovers = std::fs::read_dir(&dir).unwrap().count();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(child.status.success());
+    for (n, contents) in contents.iter().enumerate() {
+        assert_eq!(contents, &format!("x = {}\n# y = {}\n", n, n));
+    }
+    assert_eq!(leftovers, 64);
+    assert_eq!(String::from_utf8(child.stderr).unwrap(), "lines=128 matched=64 changed=64 mode=toggle\n");
+}
+
+#[test]
+fn git_modified_selects_staged_changes() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-git-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let git = |args: &[&str]| {
+        let status = std::process::Command::new("git")
+            .arg("-C").arg(&dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    };
+    let path = dir.join("a.py");
+    git(&["init", "-q"]);
+    std::fs::write(&path, "a = 1\nb = 2\nc = 3\nd = 4\n").unwrap();
+    git(&["add", "a.py"]);
+    git(&["commit", "-q", "-m", "initial"]);
+    std::fs::write(&path, "a = 1\nb = 20\nc = 3\nd = 40\n").unwrap();
+    git(&["add", "a.py"]);
+
+    let child = toggle_comment().arg("--git-modified").arg(&path).output().unwrap();
+    let outside = dir.with_extension("plain");
+    std::fs::create_dir_all(&outside).unwrap();
+    std::fs::write(outside.join("b.py"), "x\n").unwrap();
+    let untracked = toggle_comment().arg("--git-modified").arg(outside.join("b.py")).output().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_dir_all(&outside).unwrap();
+
+    assert!(child.status.success());
+    assert_eq!(String::from_utf8(child.stdout).unwrap(), "a = 1\n# b = 20\nc = 3\n# d = 40\n");
+    assert!(!untracked.status.success());
+    assert!(String::from_utf8(untracked.stderr).unwrap().contains("git diff failed"));
+}
+
+#[cfg(unix)]
+#[test]
+fn in_place_edits_symlink_target() {
+    let dir = std::env::temp_dir().join(format!("toggle-comment-symlink-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("real")).unwrap();
+    let target = dir.join("real").join("a.py");
+    let link = dir.join("link.py");
+    std::fs::write(&target, "x = 1\n").unwrap();
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let refused = toggle_comment().arg("-i").arg("--no-follow-symlinks").arg("1").arg(&link).output().unwrap();
+    let untouched = std::fs::read_to_string(&target).unwrap();
+    let followed = toggle_comment().arg("-i").arg("1").arg(&link).output().unwrap();
+    let is_symlink = std::fs::symlink_metadata(&link).unwrap().file_type().is_symlink();
+    let contents = std::fs::read_to_string(&target).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!refused.status.success());
+    assert!(String::from_utf8(refused.stderr).unwrap().contains("is a symlink"));
+    assert_eq!(untouched, "x = 1\n");
+    assert!(followed.status.success());
+    assert!(is_symlink);
+    assert_eq!(contents, "# x = 1\n");
+}
+
+#[test]
+fn emit_plan_describes_each_block() {
+    let output = run_on_stdin(&["--emit-plan", "/start/,/end/"], "start\nend\nx\n# start\n# end\n");
+    assert_eq!(output, concat!(
+        r##"[{"start":1,"end":2,"direction":"comment","lines":["# start","# end"]},"##,
+        r##"{"start":4,"end":5,"direction":"uncomment","lines":["start","end"]}]"##,
+        "\n",
+    ));
+}
+
+#[test]
+fn mark_whitespace_when_line_buffered() {
+    assert_eq!(run_on_stdin(&["--line-buffered", "--mark-whitespace", "--mode", "comment", "/^/"], "a\n  \n\n"), "# a\n# (whitespace)\n\n");
+}
+
+#[test]
+fn occurrence_zero_is_a_usage_error() {
+    let child = toggle_comment().args(["--occurrence", "0", "/frog/", "examples/poem.txt"]).output().unwrap();
+    assert_eq!(child.status.code(), Some(2));
+    assert!(child.stdout.is_empty());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("--occurrence"));
+}
+
+#[test]
+fn nth_zero_is_a_usage_error() {
+    let child = toggle_comment().args(["--nth", "0", "/frog/", "examples/poem.txt"]).output().unwrap();
+    assert_eq!(child.status.code(), Some(2));
+    assert!(child.stdout.is_empty());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("--nth"));
+}
+
+#[test]
+fn repeat_marker_zero_is_a_usage_error() {
+    let child = toggle_comment().args(["--repeat-marker", "0", "1", "examples/poem.txt"]).output().unwrap();
+    assert_eq!(child.status.code(), Some(2));
+    assert!(child.stdout.is_empty());
+    assert!(String::from_utf8(child.stderr).unwrap().contains("--repeat-marker"));
+}
+
+#[test]
+fn requires_regex_is_parsed_like_an_address() {
+    assert_eq!(run_on_stdin(&["--requires", r"/a\//", ""], "a/b\nab\n"), "# a/b\nab\n");
+    let unbounded = toggle_comment().args(["--max-regex-size", "1024", "--requires", "/a{1000}{1000}/", "", "examples/poem.txt"]).output().unwrap();
+    assert!(!unbounded.status.success());
+    assert!(String::from_utf8(unbounded.stderr).unwrap().contains("size limit"));
+}
+
+#[test]
+fn stats_reports_the_mode_set_by_other_options() {
+    let stats = |extra: &[&str]| {
+        let child = toggle_comment().arg("--stats").args(extra).arg("1").arg("examples/poem.txt").output().unwrap();
+        assert!(child.status.success());
+        String::from_utf8(child.stderr).unwrap()
+    };
+    assert!(stats(&["--disable"]).ends_with("mode=comment\n"));
+    assert!(stats(&["--enable"]).ends_with("mode=uncomment\n"));
+    assert!(stats(&["--reindent", "4"]).ends_with("mode=uncomment\n"));
+    assert!(stats(&["--mode", "comment"]).ends_with("mode=comment\n"));
+}
@@ -82,3 +82,11 @@ pattern_test_force_comment!(#[ignore] single_multiple, "2,~5");
 pattern_test_force_comment!(#[ignore] regex_multiple, "/you/,~5");
 pattern_test_force_comment!(#[ignore] negated_single_multiple, "2,~5!");
 pattern_test_force_comment!(#[ignore] negated_regex_multiple, "/you/,~5!");
+
+pattern_test_force_comment!(regex_range_end_matches_start_line, "/nobody/,/you/");
+pattern_test_force_comment!(negated_regex_range_end_matches_start_line, "/nobody/,/you/!");
+
+pattern_test_force_comment!(regex_range_end_never_matches, "/nobody/,/zebra/");
+pattern_test_force_comment!(line_range_end_never_matches, "3,/zebra/");
+pattern_test_force_comment!(negated_regex_range_end_never_matches, "/nobody/,/zebra/!");
+pattern_test_force_comment!(negated_line_range_end_never_matches, "3,/zebra/!");